@@ -1,13 +1,23 @@
-use ai2048_lib::game_logic::{GameEngine, Grid, MOVES};
-use ai2048_lib::searcher::{self, SearchResult};
+use ai2048_lib::game_logic::{self, GameEngine, Grid, MOVES};
+use ai2048_lib::heuristic::Heuristic;
+use ai2048_lib::mcts::MctsSearcher;
+use ai2048_lib::monte_carlo::RolloutAgent;
+use ai2048_lib::ntuple::NTupleNetwork;
+use ai2048_lib::record::GameRecord;
+use ai2048_lib::searcher::{self, DepthLimit, Explanation, SearchResult, Searcher};
 use cfg_if::cfg_if;
 use chrono::prelude::*;
 use chrono::Duration;
-use std::collections::HashMap;
-use std::fmt::{self, Write};
 use crossbeam::thread;
 use crossbeam::unbounded;
-use crossbeam::{SendError, RecvError};
+use crossbeam::{RecvError, SendError};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use structopt::StructOpt;
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
@@ -16,19 +26,31 @@ cfg_if! {
     }
 }
 
-const MIN_PROBABILITY: f32 = 0.001;
-
 #[derive(Debug)]
 enum Error {
     Fmt(fmt::Error),
     Recv(RecvError),
-    Send(SendError<Signal>),
+    Send,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Score {
+    total: u32,
+    last_move: u32,
 }
 
 #[derive(Debug)]
 enum Signal {
     Stop,
-    Display(SearchResult, i32, chrono::Duration, chrono::Duration),
+    Quit,
+    Display(
+        SearchResult,
+        Option<Box<Explanation>>,
+        i32,
+        Score,
+        chrono::Duration,
+        chrono::Duration,
+    ),
 }
 
 impl From<fmt::Error> for Error {
@@ -44,12 +66,176 @@ impl From<RecvError> for Error {
 }
 
 impl From<SendError<Signal>> for Error {
-    fn from(error: SendError<Signal>) -> Self {
-        Error::Send(error)
+    fn from(_: SendError<Signal>) -> Self {
+        Error::Send
+    }
+}
+
+/// Plays a game of 2048 and shows how the AI sees every position.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ai2048-bin")]
+struct Opt {
+    /// Search algorithm
+    #[structopt(
+        short,
+        long,
+        default_value = "expectimax",
+        possible_values = &["expectimax", "mcts", "monte-carlo"]
+    )]
+    algorithm: Algorithm,
+    /// Cutoff probability of expectimax searches
+    #[structopt(short, long, default_value = "0.001")]
+    probability: f32,
+    /// Search exactly this deep with expectimax, instead of deeper as the game goes on
+    #[structopt(short, long, conflicts_with = "max-depth")]
+    depth: Option<u8>,
+    /// Deepest expectimax search as the game goes on
+    #[structopt(long, default_value = "14")]
+    max_depth: u8,
+    /// Key expectimax's transposition table by symmetry, so that rotated and reflected
+    /// positions share entries
+    #[structopt(long)]
+    canonical_keys: bool,
+    /// File with an n-tuple network trained by ai2048-train, to evaluate expectimax's leaf
+    /// positions with instead of the default heuristic
+    #[structopt(short, long, parse(from_os_str))]
+    network: Option<PathBuf>,
+    /// Seed of the random tiles. Random if omitted. Expectimax searches on all cores, and the
+    /// threads see each other's cached evaluations in no fixed order, so evaluations differ
+    /// slightly from run to run. Given a seed, expectimax searches on a single thread instead,
+    /// so that the same seed always replays the same game
+    #[structopt(short, long)]
+    seed: Option<u64>,
+    /// Position to start from instead of two random tiles: 16 numbers row by row, with 0 for
+    /// empty cells, like the grid the display shows
+    #[structopt(short, long, parse(try_from_str = parse_grid))]
+    grid: Option<Grid>,
+    /// Print a line per move instead of redrawing the screen
+    #[structopt(long)]
+    plain: bool,
+    /// Show the line of play expectimax expects, what the heuristic sees at the end of it, and
+    /// the chance of game over within the search horizon
+    #[structopt(short, long)]
+    explain: bool,
+    /// File to save a record of the game to once it's over. Ctrl-C then stops the game after the
+    /// move being searched, and saves the record of the moves so far
+    #[structopt(short, long, parse(from_os_str))]
+    record: Option<PathBuf>,
+}
+
+fn parse_grid(s: &str) -> Result<Grid, String> {
+    Grid::from_display(s).map_err(|e| format!("Invalid grid: {}", e))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    Expectimax,
+    Mcts,
+    MonteCarlo,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "expectimax" => Ok(Algorithm::Expectimax),
+            "mcts" => Ok(Algorithm::Mcts),
+            "monte-carlo" => Ok(Algorithm::MonteCarlo),
+            _ => Err(format!("Unknown algorithm: {}", s)),
+        }
+    }
+}
+
+enum Agent {
+    Expectimax(Searcher, f32),
+    Network(Searcher<NTupleNetwork>, f32),
+    Mcts(MctsSearcher),
+    MonteCarlo(RolloutAgent),
+}
+
+impl Agent {
+    // The agents' own randomness must not change the tiles of the game, so they get a different
+    // seed
+    fn new(opt: &Opt, seed: u64) -> Agent {
+        match opt.algorithm {
+            Algorithm::Expectimax => {
+                let depth_limit = match opt.depth {
+                    Some(depth) => DepthLimit::Fixed(depth),
+                    None => DepthLimit::Variable(opt.max_depth),
+                };
+                match &opt.network {
+                    Some(path) => {
+                        let network =
+                            NTupleNetwork::load(path).expect("Failed to read the network");
+                        let searcher = Searcher::with_heuristic(network)
+                            .with_depth_limit(depth_limit)
+                            .with_canonical_keys(opt.canonical_keys)
+                            .with_deterministic(opt.seed.is_some());
+                        Agent::Network(searcher, opt.probability)
+                    }
+                    None => {
+                        let searcher = Searcher::new()
+                            .with_depth_limit(depth_limit)
+                            .with_canonical_keys(opt.canonical_keys)
+                            .with_deterministic(opt.seed.is_some());
+                        Agent::Expectimax(searcher, opt.probability)
+                    }
+                }
+            }
+            Algorithm::Mcts => Agent::Mcts(MctsSearcher::new().with_seed(!seed)),
+            Algorithm::MonteCarlo => Agent::MonteCarlo(RolloutAgent::new().with_seed(!seed)),
+        }
+    }
+
+    // Only expectimax can explain its moves
+    fn search(&mut self, grid: Grid, explain: bool) -> (SearchResult, Option<Explanation>) {
+        match self {
+            Agent::Expectimax(searcher, min_probability) => {
+                expectimax(searcher, grid, *min_probability, explain)
+            }
+            Agent::Network(searcher, min_probability) => {
+                expectimax(searcher, grid, *min_probability, explain)
+            }
+            Agent::Mcts(searcher) => (searcher.search(grid), None),
+            Agent::MonteCarlo(agent) => (agent.search(grid), None),
+        }
+    }
+}
+
+fn expectimax<H: Heuristic>(
+    searcher: &mut Searcher<H>,
+    grid: Grid,
+    min_probability: f32,
+    explain: bool,
+) -> (SearchResult, Option<Explanation>) {
+    if explain {
+        let explanation = searcher.explain(grid, min_probability);
+        (explanation.result.clone(), Some(explanation))
+    } else {
+        (searcher.search(grid, min_probability), None)
     }
 }
 
 fn main() {
+    let opt = Opt::from_args();
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    let mut agent = Agent::new(&opt, seed);
+    let plain = opt.plain;
+    let explain = opt.explain;
+    let start_grid = opt.grid;
+    let record_path = opt.record;
+    if plain {
+        // The full display shows it with every move
+        println!("Seed: {}", seed);
+    }
+    let quit = Arc::new(AtomicBool::new(false));
+    if record_path.is_some() {
+        let quit = quit.clone();
+        ctrlc::set_handler(move || quit.store(true, Ordering::Relaxed))
+            .expect("Failed to handle Ctrl-C");
+    }
+
     thread::scope(|s| {
         let (tx, rx) = unbounded();
 
@@ -59,39 +245,87 @@ fn main() {
                 let message = rx.recv()?;
 
                 match message {
-                    Signal::Stop => break,
-                    Signal::Display(result, moves, one, overall) => {
+                    Signal::Stop => {
+                        println!("Game over!");
+                        break;
+                    }
+                    Signal::Quit => {
+                        println!("Stopped before the game was over");
+                        break;
+                    }
+                    Signal::Display(result, explanation, moves, score, one, overall) => {
                         let entry = times.entry(result.depth).or_insert((0, Duration::zero()));
                         *entry = (entry.0 + 1, entry.1 + one);
-                        println!("{}", build_display(&result, moves, one, overall, &times)?);
+                        if plain {
+                            print!("{}", build_line(&result, moves, score, one)?);
+                            if let Some(explanation) = &explanation {
+                                print!("{}", build_explanation(explanation)?);
+                            }
+                        } else {
+                            println!(
+                                "{}",
+                                build_display(
+                                    &result,
+                                    explanation.as_deref(),
+                                    seed,
+                                    moves,
+                                    score,
+                                    one,
+                                    overall,
+                                    &times
+                                )?
+                            );
+                        }
                     }
                 };
             }
-            println!("Game over!");
             Ok(())
         });
 
         let compute_loop = s.spawn(move |_| {
             let game_engine = GameEngine::new();
-            let mut grid = Grid::default().add_random_tile().add_random_tile();
+            let mut rng = game_logic::seeded_rng(seed);
+            let mut grid = start_grid.unwrap_or_else(|| {
+                Grid::default()
+                    .add_random_tile_with(&mut rng)
+                    .add_random_tile_with(&mut rng)
+            });
+            let mut record = GameRecord::new(grid);
             let start_overall = Utc::now();
             let mut moves = 0;
+            let mut score = Score::default();
             loop {
                 moves += 1;
                 let start_one = Utc::now();
-                let result = searcher::search(grid, MIN_PROBABILITY);
+                let (result, explanation) = agent.search(grid, explain);
                 let end = Utc::now();
+                let best_move = result.best_move;
+                let outcome = best_move.map(|mv| game_engine.make_scored_move(grid, mv));
+                if let Some(outcome) = outcome {
+                    score.total += outcome.score;
+                    score.last_move = outcome.score;
+                }
                 tx.send(Signal::Display(
-                    result.clone(),
+                    result,
+                    explanation.map(Box::new),
                     moves,
+                    score,
                     end - start_one,
                     end - start_overall,
                 ))?;
 
-                if let Some(mv) = result.best_move {
-                    grid = game_engine.make_move(grid, mv).add_random_tile();
-                } else {
-                    tx.send(Signal::Stop)?;
+                if let (Some(mv), Some(outcome)) = (best_move, outcome) {
+                    grid = outcome.grid.add_random_tile_with(&mut rng);
+                    record
+                        .push(mv, outcome.grid, grid)
+                        .expect("Exactly one tile spawns after every move");
+                }
+                let over = best_move.is_none();
+                if over || quit.load(Ordering::Relaxed) {
+                    if let Some(path) = &record_path {
+                        record.save(path).expect("Failed to save the game record");
+                    }
+                    tx.send(if over { Signal::Stop } else { Signal::Quit })?;
                     let res: Result<(), Error> = Ok(());
                     return res;
                 }
@@ -100,12 +334,80 @@ fn main() {
 
         display_loop.join().unwrap().unwrap();
         compute_loop.join().unwrap().unwrap();
-    }).unwrap();
+    })
+    .unwrap();
+}
+
+// A line per move for `--plain`, and the final grid once the game is over
+fn build_line(
+    result: &SearchResult,
+    moves: i32,
+    score: Score,
+    one: chrono::Duration,
+) -> Result<String, fmt::Error> {
+    let mut s = String::new();
+    match result.best_move {
+        Some(mv) => writeln!(
+            &mut s,
+            "Move {:>5}: {:>5}; Score: {:>7}; Depth: {:>2}; {:>8.3} ms",
+            moves,
+            mv,
+            score.total,
+            result.depth,
+            one.num_nanoseconds().unwrap() as f32 / 1_000_000.0f32
+        )?,
+        None => {
+            writeln!(&mut s, "{}", result.root_grid)?;
+            writeln!(&mut s, "Score: {}", score.total)?;
+        }
+    }
+    Ok(s)
+}
+
+// What expectimax expects to happen after its move, for `--explain`
+fn build_explanation(explanation: &Explanation) -> Result<String, fmt::Error> {
+    let mut s = String::new();
+    if explanation.principal_variation.is_empty() {
+        return Ok(s);
+    }
+    writeln!(
+        &mut s,
+        "Expected line ({:.2}% chance of game over within the horizon):",
+        explanation.game_over_probability * 100.0
+    )?;
+    for ply in &explanation.principal_variation {
+        write!(&mut s, "{:>8}: {:>16.0}", ply.mv, ply.eval)?;
+        match ply.spawn {
+            Some((spawn, probability)) => writeln!(
+                &mut s,
+                "  then {:>2} at row {}, column {} ({:.1}%)",
+                spawn.value,
+                spawn.index / 4 + 1,
+                spawn.index % 4 + 1,
+                probability * 100.0
+            )?,
+            None => writeln!(&mut s)?,
+        }
+    }
+    if let Some(features) = explanation.features {
+        writeln!(&mut s, "Heuristic at the end of the line:")?;
+        writeln!(&mut s, "  Not lost:     {:>12.0}", features.not_lost)?;
+        writeln!(&mut s, "  Monotonicity: {:>12.0}", features.monotonicity)?;
+        writeln!(&mut s, "  Empty:        {:>12.0}", features.empty)?;
+        writeln!(&mut s, "  Adjacency:    {:>12.0}", features.adjacent)?;
+        writeln!(&mut s, "  Sum:          {:>12.0}", features.sum)?;
+        writeln!(&mut s, "  Total:        {:>12.0}", features.total())?;
+    }
+    Ok(s)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_display(
     result: &SearchResult,
+    explanation: Option<&Explanation>,
+    seed: u64,
     moves: i32,
+    score: Score,
     one: chrono::Duration,
     overall: chrono::Duration,
     times: &HashMap<u8, (i32, chrono::Duration)>,
@@ -115,6 +417,15 @@ fn build_display(
 
     writeln!(&mut s, "{}", result.root_grid)?;
 
+    writeln!(
+        &mut s,
+        "Score: {} (+{}, {:.1} per move)",
+        score.total,
+        score.last_move,
+        f64::from(score.total) / f64::from(moves)
+    )?;
+    writeln!(&mut s)?;
+
     for mv in &MOVES {
         write!(&mut s, "{:>8}: ", mv)?;
         match result.move_evaluations.get(mv) {
@@ -125,8 +436,13 @@ fn build_display(
 
     writeln!(&mut s)?;
 
+    if let Some(explanation) = explanation.filter(|e| !e.principal_variation.is_empty()) {
+        writeln!(&mut s, "{}", build_explanation(explanation)?)?;
+    }
+
+    writeln!(&mut s, "Seed: {}", seed)?;
     writeln!(&mut s, "Depth: {}", result.depth)?;
-    writeln!(&mut s, "Cutoff probability: {}", MIN_PROBABILITY)?;
+    writeln!(&mut s, "Cutoff probability: {}", result.min_probability)?;
 
     writeln!(&mut s)?;
 
@@ -154,6 +470,18 @@ fn build_display(
         result.stats.cache_hits,
         f64::from(result.stats.cache_hits) * 100.0 / f64::from(result.stats.nodes)
     )?;
+    writeln!(
+        &mut s,
+        "  from earlier searches:{:>8} [{:>4.1}%]",
+        result.stats.reused_hits,
+        f64::from(result.stats.reused_hits) * 100.0 / f64::from(result.stats.nodes)
+    )?;
+    writeln!(
+        &mut s,
+        "  symmetric positions:  {:>8} [{:>4.1}%]",
+        result.stats.symmetric_hits,
+        f64::from(result.stats.symmetric_hits) * 100.0 / f64::from(result.stats.nodes)
+    )?;
     writeln!(
         &mut s,
         "Heuristic:              {:>8} [{:>4.1}%]",
@@ -177,7 +505,10 @@ fn build_display(
         &mut s,
         "------+------------------+----------------+-------------"
     )?;
-    for depth in searcher::MIN_DEPTH..=searcher::MAX_DEPTH {
+    // Fixed depths and tree searches can go outside the variable range
+    let shallowest = times.keys().cloned().min().unwrap_or(searcher::MIN_DEPTH);
+    let deepest = times.keys().cloned().max().unwrap_or(searcher::MAX_DEPTH);
+    for depth in shallowest.min(searcher::MIN_DEPTH)..=deepest.max(searcher::MAX_DEPTH) {
         let (moves_d, time) = times.get(&depth).cloned().unwrap_or((0, Duration::zero()));
         let time_avg = match time.num_milliseconds() as f32 / moves_d as f32 {
             nan if nan.is_nan() => String::default(),
@@ -1,5 +1,5 @@
 use ai2048_lib::game_logic::{GameEngine, Grid, Move};
-use ai2048_lib::heuristic::Heuristic;
+use ai2048_lib::heuristic::{Heuristic, RowHeuristic};
 use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
 use lazy_static::lazy_static;
@@ -60,7 +60,7 @@ fn game_over(c: &mut Criterion) {
 }
 
 fn heuristic(c: &mut Criterion) {
-    let heuristic = Heuristic::new();
+    let heuristic = RowHeuristic::new();
     c.bench_function("eval", move |b| b.iter(|| heuristic.eval(*TEST_GRID)));
 }
 
@@ -0,0 +1,666 @@
+//! Boards of sizes other than 4x4, such as 3x3, 5x5 or 4 rows of 5.
+//!
+//! `Grid` and `Searcher` are built around the 4x4 board and stay its fast path. `Board` trades
+//! some of that speed for any size from 2x2 to 5x5, with move tables for every row length and an
+//! expectimax search of its own.
+
+use crate::game_logic::{slide_line, to_log, GridError, Move, SpawnRule, MOVES};
+use crate::heuristic::{self, HeuristicWeights};
+use crate::searcher::{Cache, DepthLimit, SearchResult, SearchStats};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The fewest tiles a row or column of a `Board` can have
+pub const MIN_SIDE: usize = 2;
+/// The most tiles a row or column of a `Board` can have
+pub const MAX_SIDE: usize = 5;
+
+// Bits of a cell, which holds the base 2 logarithm of its tile
+const CELL_BITS: usize = 5;
+const CELL_MASK: u8 = 0b1_1111;
+// The biggest exponent a cell can hold. Every merge up to a tile of 2^n needs a cell for each
+// smaller tile on the way, so no board of up to 25 cells gets past 2^26.
+const MAX_LOG: u8 = 31;
+// The biggest exponent the move tables take, as they index lines by nibbles
+const MAX_TABLE_LOG: u8 = 0b1111;
+
+/// A board of 3 rows of 3 tiles
+pub type Board3x3 = Board<3, 3>;
+/// A board of 5 rows of 5 tiles
+pub type Board5x5 = Board<5, 5>;
+/// A board of 4 rows of 5 tiles
+pub type Board4x5 = Board<4, 5>;
+
+/// A board of `ROWS` rows of `COLUMNS` tiles, with 2 to 5 tiles a side.
+///
+/// Every tile is packed into 5 bits as the base 2 logarithm of its value, which is enough for any
+/// tile a board this small can reach. Cells go row by row from the top left corner, starting
+/// from the lowest bits.
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Default)]
+pub struct Board<const ROWS: usize, const COLUMNS: usize> {
+    cells: u128,
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> fmt::Display for Board<ROWS, COLUMNS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.unpack_human().iter() {
+            for &tile in row {
+                write!(f, "{:>6}", tile)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> fmt::Debug for Board<ROWS, COLUMNS> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.unpack_human())
+    }
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> Board<ROWS, COLUMNS> {
+    // Stops boards of unsupported sizes from compiling
+    const SIZE_CHECK: () = assert!(
+        ROWS >= MIN_SIDE && ROWS <= MAX_SIDE && COLUMNS >= MIN_SIDE && COLUMNS <= MAX_SIDE,
+        "Boards must have 2 to 5 tiles a side"
+    );
+    const CELLS: usize = ROWS * COLUMNS;
+
+    /// Creates a board from an array of human-looking numbers. If a tile fails to be a power of
+    /// 2, returns the first such tile in the error.
+    pub fn from_human(tiles: [[u32; COLUMNS]; ROWS]) -> Result<Self, GridError> {
+        let () = Self::SIZE_CHECK;
+        let mut board = Self::default();
+        for (row, line) in tiles.iter().enumerate() {
+            for (column, &tile) in line.iter().enumerate() {
+                let log = to_log(tile).ok_or(GridError::InvalidTile { row, column, tile })?;
+                board = board.with_log(row * COLUMNS + column, log);
+            }
+        }
+        Ok(board)
+    }
+
+    /// Parses a board from the representation given by the `Display` implementation
+    pub fn from_display(s: &str) -> Result<Self, GridError> {
+        let numbers = s
+            .split(|c: char| !c.is_numeric())
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        if numbers.len() != Self::CELLS {
+            return Err(GridError::WrongTileCount {
+                expected: Self::CELLS,
+                count: numbers.len(),
+            });
+        }
+
+        let mut tiles = [[0u32; COLUMNS]; ROWS];
+        for (index, (tile, number)) in tiles.iter_mut().flatten().zip(&numbers).enumerate() {
+            *tile = number.parse().map_err(|_| GridError::InvalidNumber {
+                row: index / COLUMNS,
+                column: index % COLUMNS,
+            })?;
+        }
+        Self::from_human(tiles)
+    }
+
+    /// Unpacks a human-readable representation of the board, row by row
+    pub fn unpack_human(self) -> [[u32; COLUMNS]; ROWS] {
+        let mut tiles = [[0u32; COLUMNS]; ROWS];
+        for (cell, tile) in tiles.iter_mut().flatten().enumerate() {
+            *tile = match self.log(cell) {
+                0 => 0,
+                log => 1 << log,
+            };
+        }
+        tiles
+    }
+
+    /// Number of empty cells
+    pub fn count_empty(self) -> usize {
+        (0..Self::CELLS).filter(|&cell| self.log(cell) == 0).count()
+    }
+
+    /// Number of distinct tiles on the board, not counting empty cells
+    pub fn count_distinct_tiles(self) -> u8 {
+        (0..Self::CELLS)
+            .map(|cell| self.log(cell))
+            .filter(|&log| log != 0)
+            .collect::<HashSet<_>>()
+            .len() as u8
+    }
+
+    /// The biggest tile on the board
+    pub fn biggest_tile(self) -> u32 {
+        match (0..Self::CELLS).map(|cell| self.log(cell)).max() {
+            Some(0) | None => 0,
+            Some(log) => 1 << log,
+        }
+    }
+
+    /// Creates a new board with a tile chosen by `spawn_rule` added to a random empty cell
+    pub fn add_random_tile<R: Rng + ?Sized>(self, spawn_rule: &SpawnRule, rng: &mut R) -> Self {
+        let position = rng.gen_range(0, self.count_empty());
+        let cell = (0..Self::CELLS)
+            .filter(|&cell| self.log(cell) == 0)
+            .nth(position)
+            .expect("Picked one of the empty cells");
+        self.with_log(cell, spawn_rule.choose_log(rng))
+    }
+
+    /// Returns the board that would result from making `mv`
+    pub fn make_move(self, mv: Move) -> Self {
+        self.slide(mv).0
+    }
+
+    /// The points `mv` would gain
+    pub fn move_score(self, mv: Move) -> u32 {
+        self.slide(mv).1
+    }
+
+    /// Returns all possible player moves
+    pub fn player_moves(self) -> impl Iterator<Item = (Move, Self)> {
+        MOVES.iter().filter_map(move |&mv| {
+            let board = self.make_move(mv);
+            if board == self {
+                None
+            } else {
+                Some((mv, board))
+            }
+        })
+    }
+
+    fn log(self, cell: usize) -> u8 {
+        (self.cells >> (cell * CELL_BITS)) as u8 & CELL_MASK
+    }
+
+    fn with_log(self, cell: usize, log: u8) -> Self {
+        let shift = cell * CELL_BITS;
+        Board {
+            cells: (self.cells & !(u128::from(CELL_MASK) << shift)) | (u128::from(log) << shift),
+        }
+    }
+
+    // Every row or column goes through the move table for its length, one at a time. Lines with
+    // tiles beyond 32768 don't fit the table, and slide on the fly instead, like the rows of a
+    // `Grid` that don't fit its cache.
+    fn slide(self, mv: Move) -> (Self, u32) {
+        let () = Self::SIZE_CHECK;
+        let (lines, length) = match mv {
+            Move::Left | Move::Right => (ROWS, COLUMNS),
+            Move::Up | Move::Down => (COLUMNS, ROWS),
+        };
+        let table = line_table(length);
+        let mut board = Self::default();
+        let mut score = 0;
+        for line in 0..lines {
+            // The cells of the line, starting from the side the tiles move to
+            let cell = |i: usize| {
+                let i = match mv {
+                    Move::Left | Move::Up => i,
+                    Move::Right | Move::Down => length - 1 - i,
+                };
+                match mv {
+                    Move::Left | Move::Right => line * COLUMNS + i,
+                    Move::Up | Move::Down => i * COLUMNS + line,
+                }
+            };
+            let mut from = [0u8; MAX_SIDE];
+            for (i, log) in from.iter_mut().take(length).enumerate() {
+                *log = self.log(cell(i));
+            }
+            let mut to = [0u8; MAX_SIDE];
+            if from.iter().all(|&log| log <= MAX_TABLE_LOG) {
+                let index = from[..length]
+                    .iter()
+                    .enumerate()
+                    .fold(0usize, |index, (i, &log)| {
+                        index | usize::from(log) << (i * 4)
+                    });
+                let moved = table.moved[index];
+                for (i, log) in to.iter_mut().take(length).enumerate() {
+                    *log = (moved >> (i * CELL_BITS)) as u8 & CELL_MASK;
+                }
+                score += table.score[index];
+            } else {
+                score += slide_line(&from[..length], &mut to[..length], MAX_LOG);
+            }
+            for (i, &log) in to.iter().take(length).enumerate() {
+                board = board.with_log(cell(i), log);
+            }
+        }
+        (board, score)
+    }
+
+    // The tiles of every row and then every column, with the length of each
+    fn lines(self) -> impl Iterator<Item = ([u8; MAX_SIDE], usize)> {
+        let rows = (0..ROWS).map(move |row| {
+            let mut line = [0; MAX_SIDE];
+            for (column, tile) in line.iter_mut().take(COLUMNS).enumerate() {
+                *tile = self.log(row * COLUMNS + column);
+            }
+            (line, COLUMNS)
+        });
+        let columns = (0..COLUMNS).map(move |column| {
+            let mut line = [0; MAX_SIDE];
+            for (row, tile) in line.iter_mut().take(ROWS).enumerate() {
+                *tile = self.log(row * COLUMNS + column);
+            }
+            (line, ROWS)
+        });
+        rows.chain(columns)
+    }
+}
+
+// Every line of a given length whose tiles fit a nibble, moved towards its first tile, together
+// with the points gained by the move. Lines are indexed by their nibbles and moved into cells of
+// a `Board`, first tile in the lowest bits, as two 32768s merge into a tile that needs 5 bits.
+struct LineTable {
+    moved: Box<[u32]>,
+    score: Box<[u32]>,
+}
+
+impl LineTable {
+    fn new(length: usize) -> Self {
+        let count = 1 << (length * 4);
+        let mut moved = vec![0; count];
+        let mut score = vec![0; count];
+        for index in 0..count {
+            let mut from = [0u8; MAX_SIDE];
+            for (i, tile) in from.iter_mut().take(length).enumerate() {
+                *tile = (index >> (i * 4)) as u8 & 0b1111;
+            }
+            let mut to = [0u8; MAX_SIDE];
+            score[index] = slide_line(&from[..length], &mut to[..length], MAX_LOG);
+            moved[index] = to[..length]
+                .iter()
+                .enumerate()
+                .fold(0, |packed, (i, &log)| {
+                    packed | u32::from(log) << (i * CELL_BITS)
+                });
+        }
+        LineTable {
+            moved: moved.into(),
+            score: score.into(),
+        }
+    }
+}
+
+// Built the first time a board with lines of that length moves. The table for 5 tiles takes
+// 8 MB, so there's no point building it for boards that never use it.
+lazy_static! {
+    static ref LINES_2: LineTable = LineTable::new(2);
+    static ref LINES_3: LineTable = LineTable::new(3);
+    static ref LINES_4: LineTable = LineTable::new(4);
+    static ref LINES_5: LineTable = LineTable::new(5);
+}
+
+fn line_table(length: usize) -> &'static LineTable {
+    match length {
+        2 => &LINES_2,
+        3 => &LINES_3,
+        4 => &LINES_4,
+        5 => &LINES_5,
+        _ => unreachable!("Boards have 2 to 5 tiles a side"),
+    }
+}
+
+/// Searches boards of any size with expectimax. Leaves are evaluated with the features of
+/// `RowHeuristic` on every row and column, computed on the fly as there's no table for them.
+/// Every search starts with an empty transposition table and runs on a single thread.
+#[derive(Clone, Debug, Default)]
+pub struct BoardSearcher {
+    spawn_rule: SpawnRule,
+    weights: HeuristicWeights,
+    depth_limit: DepthLimit,
+}
+
+impl BoardSearcher {
+    /// Initializes a searcher with the default spawn rule, weights and depth limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects new tiles to spawn according to `spawn_rule`
+    pub fn with_spawn_rule(self, spawn_rule: SpawnRule) -> Self {
+        BoardSearcher { spawn_rule, ..self }
+    }
+
+    /// Evaluates leaf positions with `weights`
+    pub fn with_weights(self, weights: HeuristicWeights) -> Self {
+        BoardSearcher { weights, ..self }
+    }
+
+    /// Limits the depth of searches. The variable depth counts distinct tiles the same way as
+    /// for 4x4 grids.
+    pub fn with_depth_limit(self, depth_limit: DepthLimit) -> Self {
+        BoardSearcher {
+            depth_limit,
+            ..self
+        }
+    }
+
+    /// The spawn rule new tiles are expected to follow
+    pub fn spawn_rule(&self) -> SpawnRule {
+        self.spawn_rule
+    }
+
+    /// Evaluates a board. Bigger is better.
+    pub fn eval<const ROWS: usize, const COLUMNS: usize>(
+        &self,
+        board: Board<ROWS, COLUMNS>,
+    ) -> f32 {
+        board
+            .lines()
+            .map(|(line, length)| heuristic::eval_row_nocache(&line[..length], &self.weights))
+            .sum()
+    }
+
+    /// Investigate a board and determine move evaluations. The search will stop recursing into
+    /// child nodes as soon as a position at least as improbable as `min_probability` is reached.
+    pub fn search<const ROWS: usize, const COLUMNS: usize>(
+        &self,
+        board: Board<ROWS, COLUMNS>,
+        min_probability: f32,
+    ) -> SearchResult<Board<ROWS, COLUMNS>> {
+        let depth = self
+            .depth_limit
+            .depth_for(board.count_distinct_tiles(), board.biggest_tile());
+        let mut search = BoardSearch {
+            searcher: self,
+            min_probability,
+            table: Cache::default(),
+            stats: SearchStats::default(),
+        };
+
+        let mut move_evaluations = board
+            .player_moves()
+            .map(|(mv, b)| (mv, search.player_move_eval(b, 1f32, depth)))
+            .collect::<Vec<_>>();
+        move_evaluations.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("Failed to sort evaluations"));
+        let best_move = move_evaluations.first().map(|&(mv, _)| mv);
+        search.stats.cache_size = search.table.len() as u32;
+
+        SearchResult {
+            root_grid: board,
+            move_evaluations: move_evaluations.into_iter().collect(),
+            best_move,
+            stats: search.stats,
+            depth,
+            min_probability,
+        }
+    }
+}
+
+// A cached evaluation, good enough for a lookup at most as deep and at most as far from the cutoff
+// probability
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    depth: u8,
+    headroom: f32,
+    eval: f32,
+}
+
+struct BoardSearch<'a, const ROWS: usize, const COLUMNS: usize> {
+    searcher: &'a BoardSearcher,
+    min_probability: f32,
+    table: Cache<Board<ROWS, COLUMNS>, Entry>,
+    stats: SearchStats,
+}
+
+impl<'a, const ROWS: usize, const COLUMNS: usize> BoardSearch<'a, ROWS, COLUMNS> {
+    fn random_move_eval(
+        &mut self,
+        board: Board<ROWS, COLUMNS>,
+        probability: f32,
+        depth: u8,
+    ) -> f32 {
+        self.stats.nodes += 1;
+        self.stats.average += 1;
+
+        board
+            .player_moves()
+            .map(|(_, b)| self.player_move_eval(b, probability, depth))
+            .fold(0f32, f32::max)
+    }
+
+    fn player_move_eval(
+        &mut self,
+        board: Board<ROWS, COLUMNS>,
+        probability: f32,
+        depth: u8,
+    ) -> f32 {
+        self.stats.nodes += 1;
+
+        if depth == 0 || probability < self.min_probability {
+            self.stats.evals += 1;
+            return self.searcher.eval(board);
+        }
+
+        let headroom = probability / self.min_probability;
+        if let Some(entry) = self.table.get(&board) {
+            if entry.depth >= depth && entry.headroom >= headroom {
+                self.stats.cache_hits += 1;
+                return entry.eval;
+            }
+        }
+
+        self.stats.average += 1;
+
+        let count = board.count_empty() as f32;
+        let spawn_rule = self.searcher.spawn_rule;
+        let mut eval = 0f32;
+        for &(tile, tile_probability) in spawn_rule.log_tiles() {
+            let prob = probability * tile_probability / count;
+            let sum = (0..Board::<ROWS, COLUMNS>::CELLS)
+                .filter(|&cell| board.log(cell) == 0)
+                .map(|cell| self.random_move_eval(board.with_log(cell, tile), prob, depth - 1))
+                .sum::<f32>();
+            eval += sum / count * tile_probability;
+        }
+
+        self.table.insert(
+            board,
+            Entry {
+                depth,
+                headroom,
+                eval,
+            },
+        );
+
+        eval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::{self, GameEngine, Grid};
+
+    #[test]
+    fn four_by_four_boards_move_like_grids() {
+        let game_engine = GameEngine::new();
+        let mut rng = game_logic::seeded_rng(11);
+        for _ in 0..200 {
+            let mut grid = Grid::default();
+            for _ in 0..rng.gen_range(1, 14) {
+                grid = grid.add_random_tile_with(&mut rng);
+            }
+            let board = Board::<4, 4>::from_human(grid.unpack_human()).unwrap();
+
+            for &mv in &MOVES {
+                let moved = board.make_move(mv);
+
+                assert_eq!(
+                    moved.unpack_human(),
+                    game_engine.make_move(grid, mv).unpack_human()
+                );
+                assert_eq!(board.move_score(mv), game_engine.move_score(grid, mv));
+            }
+        }
+    }
+
+    #[test]
+    fn can_move_on_3x3_board() {
+        let board = Board3x3::from_human([[2, 2, 4], [0, 4, 4], [2, 0, 2]]).unwrap();
+
+        assert_eq!(
+            board.make_move(Move::Left).unpack_human(),
+            [[4, 4, 0], [8, 0, 0], [4, 0, 0]]
+        );
+        assert_eq!(
+            board.make_move(Move::Down).unpack_human(),
+            [[0, 0, 0], [0, 2, 8], [4, 4, 2]]
+        );
+        assert_eq!(board.move_score(Move::Left), 16);
+    }
+
+    #[test]
+    fn can_move_on_4x5_board() {
+        let board = Board4x5::from_human([
+            [2, 2, 2, 2, 2],
+            [0, 0, 0, 0, 4],
+            [0, 0, 0, 0, 4],
+            [0, 0, 0, 0, 8],
+        ])
+        .unwrap();
+
+        assert_eq!(
+            board.make_move(Move::Right).unpack_human(),
+            [
+                [0, 0, 2, 4, 4],
+                [0, 0, 0, 0, 4],
+                [0, 0, 0, 0, 4],
+                [0, 0, 0, 0, 8]
+            ]
+        );
+        assert_eq!(
+            board.make_move(Move::Up).unpack_human(),
+            [
+                [2, 2, 2, 2, 2],
+                [0, 0, 0, 0, 8],
+                [0, 0, 0, 0, 8],
+                [0, 0, 0, 0, 0]
+            ]
+        );
+        assert_eq!(board.move_score(Move::Up), 8);
+    }
+
+    #[test]
+    fn can_parse_board_from_display() {
+        let board = Board5x5::from_human([
+            [2, 4, 8, 16, 32],
+            [0, 0, 0, 0, 32768],
+            [0; 5],
+            [0; 5],
+            [0, 0, 2, 0, 0],
+        ])
+        .unwrap();
+
+        assert_eq!(Board5x5::from_display(&board.to_string()), Ok(board));
+        assert_eq!(
+            Board3x3::from_display("2 4 8 16"),
+            Err(GridError::WrongTileCount {
+                expected: 9,
+                count: 4
+            })
+        );
+        assert_eq!(
+            Board3x3::from_human([[0; 3], [0, 48, 0], [0; 3]]),
+            Err(GridError::InvalidTile {
+                row: 1,
+                column: 1,
+                tile: 48
+            })
+        );
+    }
+
+    #[test]
+    fn big_tiles_move_like_grids() {
+        let game_engine = GameEngine::new();
+        let mut rng = game_logic::seeded_rng(12);
+        for _ in 0..500 {
+            let mut tiles = [[0u32; 4]; 4];
+            for tile in tiles.iter_mut().flatten() {
+                // Mostly tiles around 32768, where the move table stops
+                *tile = match rng.gen_range(0, 24) {
+                    log if log < 6 => 0,
+                    log => 1 << log.min(17),
+                };
+            }
+            let board = Board::<4, 4>::from_human(tiles).unwrap();
+            let grid = Grid::from_human(tiles).unwrap();
+
+            for &mv in &MOVES {
+                assert_eq!(
+                    board.make_move(mv).unpack_human(),
+                    game_engine.make_move(grid, mv).unpack_human()
+                );
+                assert_eq!(board.move_score(mv), game_engine.move_score(grid, mv));
+            }
+        }
+    }
+
+    #[test]
+    fn can_merge_biggest_tiles_on_5x5_board() {
+        let board = Board5x5::from_human([
+            [1 << 25, 1 << 25, 0, 0, 0],
+            [32768, 32768, 0, 0, 0],
+            [0; 5],
+            [0; 5],
+            [0; 5],
+        ])
+        .unwrap();
+
+        assert_eq!(
+            board.make_move(Move::Left).unpack_human(),
+            [
+                [1 << 26, 0, 0, 0, 0],
+                [65536, 0, 0, 0, 0],
+                [0; 5],
+                [0; 5],
+                [0; 5]
+            ]
+        );
+        assert_eq!(board.move_score(Move::Left), (1 << 26) + 65536);
+        assert_eq!(board.biggest_tile(), 1 << 25);
+    }
+
+    #[test]
+    fn can_play_3x3_game() {
+        let searcher = BoardSearcher::new().with_depth_limit(DepthLimit::Fixed(3));
+        let spawn_rule = searcher.spawn_rule();
+        let mut rng = game_logic::seeded_rng(5);
+        let mut board = Board3x3::default()
+            .add_random_tile(&spawn_rule, &mut rng)
+            .add_random_tile(&spawn_rule, &mut rng);
+
+        while let Some(mv) = searcher.search(board, 0.001).best_move {
+            board = board.make_move(mv).add_random_tile(&spawn_rule, &mut rng);
+        }
+
+        assert!(board.biggest_tile() >= 64);
+        assert_eq!(board.player_moves().count(), 0);
+    }
+
+    #[test]
+    fn can_search_5x5_board() {
+        let board =
+            Board5x5::from_human([[2, 4, 8, 16, 32], [0, 0, 0, 0, 2], [0; 5], [0; 5], [0; 5]])
+                .unwrap();
+
+        let result = BoardSearcher::new()
+            .with_depth_limit(DepthLimit::Fixed(2))
+            .search(board, 0.01);
+
+        assert_eq!(result.depth, 2);
+        assert_eq!(result.root_grid, board);
+        assert_eq!(result.move_evaluations.len(), 2);
+        assert!(result.best_move.is_some());
+        assert!(result.stats.cache_size > 0);
+    }
+}
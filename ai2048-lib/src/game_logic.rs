@@ -1,9 +1,10 @@
 //! 2048 game logic is implemented here.
 use lazy_static::lazy_static;
-use rand::{self, Rng};
+use rand::{self, Rng, SeedableRng};
+use std::cmp;
 use std::collections::HashSet;
-use std::fmt::Display;
-use std::{fmt, u16};
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Default)]
 pub(crate) struct Row(pub(crate) u16);
@@ -47,6 +48,34 @@ impl Row {
         [tile0, tile1, tile2, tile3]
     }
 
+    // Unpacks the logarithms of the tiles, adding the extension bits of the row, two per tile
+    pub(crate) fn unpack_with_ext(self, ext: u8) -> [u8; 4] {
+        let mut tiles = self.unpack();
+        for (index, tile) in tiles.iter_mut().enumerate() {
+            *tile += (ext >> (6 - index * 2)) & 0b11;
+        }
+        tiles
+    }
+
+    // Packs the logarithms of four tiles into nibbles and extension bits. Every tile must be at
+    // most `MAX_EXPONENT`.
+    fn pack_with_ext(row: [u8; 4]) -> (Row, u8) {
+        let mut nibbles = [0; 4];
+        let mut ext = 0;
+        for (index, (&tile, nibble)) in row.iter().zip(nibbles.iter_mut()).enumerate() {
+            *nibble = cmp::min(tile, MAX_NIBBLE);
+            ext |= (tile - *nibble) << (6 - index * 2);
+        }
+        (Row::pack(nibbles).unwrap(), ext)
+    }
+
+    // Cached moves are only wrong for merging two 32768s, so rows with at most one of them are fine
+    fn fits_cache(self) -> bool {
+        let x = self.0;
+        let maxed = x & (x >> 1) & (x >> 2) & (x >> 3) & 0x1111;
+        maxed & maxed.wrapping_sub(1) == 0
+    }
+
     fn reverse(self) -> Self {
         Row((self.0 >> 12)
             | ((self.0 >> 4) & 0b0000_0000_1111_0000)
@@ -61,11 +90,24 @@ pub(crate) fn all_rows() -> impl Iterator<Item = (usize, Row)> {
     (0..ROW_COUNT).map(|index| (index, Row::from_index(index)))
 }
 
-// Not much effort spent optimizing this, since it's going to be cached anyway
-fn move_row_left(row: Row) -> Row {
-    let from_row = row.unpack();
+// The biggest exponent a nibble can hold. Tiles with bigger exponents keep this value in their
+// nibble and store the rest in the grid's extension bits.
+const MAX_NIBBLE: u8 = 0b1111;
+// The biggest exponent a `Grid` can hold, that is, 262144.
+pub(crate) const MAX_EXPONENT: u8 = MAX_NIBBLE + 0b11;
 
+// Slides and merges a row of exponents to the left. Returns the new row together with the points
+// gained by merging tiles. Not much effort spent optimizing this, since the common case is cached.
+fn slide_left(from_row: [u8; 4]) -> ([u8; 4], u32) {
     let mut to_row = [0; 4];
+    let score = slide_line(&from_row, &mut to_row, MAX_EXPONENT);
+    (to_row, score)
+}
+
+// Same as `slide_left`, for rows of any length and tiles of up to `max_log`. `to_row` must be as
+// long as `from_row` and filled with zeroes.
+pub(crate) fn slide_line(from_row: &[u8], to_row: &mut [u8], max_log: u8) -> u32 {
+    let mut score = 0;
     let mut last = 0;
     let mut last_index = 0;
 
@@ -80,7 +122,11 @@ fn move_row_left(row: Row) -> Row {
         }
 
         if tile == last {
-            to_row[last_index as usize] = last + 1;
+            // Two of the biggest tiles cannot be merged on a board of the size `max_log` is
+            // meant for, so this never clamps in a real game
+            let merged = cmp::min(last + 1, max_log);
+            to_row[last_index as usize] = merged;
+            score += 1 << merged;
             last = 0;
         } else {
             to_row[last_index as usize] = last;
@@ -94,12 +140,28 @@ fn move_row_left(row: Row) -> Row {
         to_row[last_index as usize] = last;
     }
 
-    // If there is a tile which does not fit a nibble, merge into a 32768 instead
-    to_row.iter_mut().filter(|i| **i > 15).for_each(|i| *i = 15);
+    score
+}
+
+fn move_row_left(row: Row) -> Row {
+    let (mut to_row, _) = slide_left(row.unpack());
+
+    // A tile that does not fit a nibble is merged into a 32768 here. Grids where this can happen
+    // never use the cached moves.
+    to_row
+        .iter_mut()
+        .filter(|i| **i > MAX_NIBBLE)
+        .for_each(|i| *i = MAX_NIBBLE);
 
     Row::pack(to_row).unwrap()
 }
 
+// The points gained by moving a row. Moving left or right merges the same tiles, so one
+// table serves both directions.
+fn score_row(row: Row) -> u32 {
+    slide_left(row.unpack()).1
+}
+
 fn move_row_right(row: Row) -> Row {
     move_row_left(row.reverse()).reverse()
 }
@@ -162,6 +224,13 @@ lazy_static! {
         }
         vec.into()
     };
+    static ref CACHE_SCORE: Box<[u32]> = {
+        let mut vec = vec![0; ROW_COUNT];
+        for (index, row) in all_rows() {
+            vec[index] = score_row(row);
+        }
+        vec.into()
+    };
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -170,6 +239,7 @@ struct Cache {
     right: &'static [Row],
     up: &'static [Column],
     down: &'static [Column],
+    score: &'static [u32],
 }
 
 impl Cache {
@@ -194,6 +264,11 @@ impl Cache {
         let row: u16 = row.0;
         unsafe { *self.down.get_unchecked(row as usize) }
     }
+    fn lookup_score(&self, row: Row) -> u32 {
+        // Make sure row.0 is still u16
+        let row: u16 = row.0;
+        unsafe { *self.score.get_unchecked(row as usize) }
+    }
 }
 
 /// Represents a move.
@@ -224,31 +299,95 @@ impl Display for Move {
     }
 }
 
-fn to_log(n: u32) -> Option<u8> {
-    use std::f32;
+pub(crate) fn to_log(n: u32) -> Option<u8> {
+    match n {
+        0 => Some(0),
+        _ if n.is_power_of_two() => Some(n.trailing_zeros() as u8),
+        _ => None,
+    }
+}
 
-    let log = match n {
-        0 => 0f32,
-        _ => (n as f32).log2(),
-    };
+/// Why a `Grid` couldn't be created.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum GridError {
+    /// The tile is not 0 or a power of 2 the grid can hold, which is up to 262144 for `Grid`
+    InvalidTile {
+        /// The row of the tile, counting from 0 at the top
+        row: usize,
+        /// The column of the tile, counting from 0 on the left
+        column: usize,
+        /// The value of the tile
+        tile: u32,
+    },
+    /// The text of the tile is not a number that fits a `u32`
+    InvalidNumber {
+        /// The row of the tile, counting from 0 at the top
+        row: usize,
+        /// The column of the tile, counting from 0 on the left
+        column: usize,
+    },
+    /// The input has the wrong number of tiles
+    WrongTileCount {
+        /// The number of tiles the grid has
+        expected: usize,
+        /// The number of tiles found
+        count: usize,
+    },
+}
 
-    let rounded = log.round();
-    if (rounded - log) < 1e-10 {
-        Some(rounded as u8)
-    } else {
-        None
+impl fmt::Display for GridError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GridError::InvalidTile { row, column, tile } => write!(
+                f,
+                "row {}, column {}: {} is not 0 or a power of 2 the grid can hold",
+                row, column, tile
+            ),
+            GridError::InvalidNumber { row, column } => {
+                write!(f, "row {}, column {}: not a valid number", row, column)
+            }
+            GridError::WrongTileCount { expected, count } => {
+                write!(f, "expected {} tiles, found {}", expected, count)
+            }
+        }
     }
 }
 
-/// `Grid` is the game state. Limitation: can encode tiles of up to 32768.
-#[derive(Eq, PartialEq, Hash, Copy, Clone, Default)]
-pub struct Grid(u64);
+impl std::error::Error for GridError {}
+
+/// Random number generator that produces the same tiles for the same seed.
+pub type GameRng = rand_pcg::Pcg64Mcg;
+
+/// Creates a `GameRng` from a seed.
+pub fn seeded_rng(seed: u64) -> GameRng {
+    GameRng::seed_from_u64(seed)
+}
+
+/// `Grid` is the game state. Limitation: can encode tiles of up to 262144.
+///
+/// Every tile is packed into a nibble of a `u64` as the base 2 logarithm of its value. Tiles bigger
+/// than 32768 don't fit a nibble, so they store 15 in it and keep the rest of the logarithm in
+/// two extension bits. This way the overwhelming majority of grids never touch the extension.
+#[derive(Eq, PartialEq, Copy, Clone, Default)]
+pub struct Grid {
+    board: u64,
+    ext: u32,
+}
+
+impl Hash for Grid {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // The extension is almost always empty, so hashing a single `u64` is a lot faster
+        state.write_u64(self.board ^ u64::from(self.ext).rotate_left(32));
+    }
+}
 
 impl fmt::Display for Grid {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep numbers apart so that `from_display` can parse them back
+        let width = if self.biggest_tile() > 99_999 { 7 } else { 6 };
         for row in self.unpack_human().iter() {
             for &tile in row {
-                write!(f, "{number:>width$}", number = tile, width = 6)?;
+                write!(f, "{number:>width$}", number = tile, width = width)?;
             }
             writeln!(f)?;
         }
@@ -263,25 +402,31 @@ impl fmt::Debug for Grid {
             write!(f, "{:?} ", row)?;
         }
 
+        if self.ext != 0 {
+            write!(f, "+{:0>32b}", self.ext)?;
+        }
+
         Ok(())
     }
 }
 
 impl Grid {
     /// Creates a new `Grid` from an array of human-looking numbers. If a tile fails to be
-    /// a power of 2, or is larger than 32768, returns `None`.
-    pub fn from_human(grid: [[u32; 4]; 4]) -> Option<Grid> {
-        let mut rows = [Row::default(); 4];
-        for (x, &row) in grid.iter().enumerate() {
-            let mut new_row = [0u8; 4];
+    /// a power of 2, or is larger than 262144, returns the first such tile in the error.
+    pub fn from_human(grid: [[u32; 4]; 4]) -> Result<Grid, GridError> {
+        let mut log = [[0u8; 4]; 4];
+        for (x, row) in grid.iter().enumerate() {
             for (y, &tile) in row.iter().enumerate() {
-                let log = to_log(tile)?;
-                new_row[y] = log;
+                log[x][y] = to_log(tile).filter(|&log| log <= MAX_EXPONENT).ok_or(
+                    GridError::InvalidTile {
+                        row: x,
+                        column: y,
+                        tile,
+                    },
+                )?;
             }
-
-            rows[x] = Row::pack(new_row).ok()?;
         }
-        Some(Grid::from_rows(rows))
+        Ok(Grid::from_log(log).expect("Every tile was checked"))
     }
 
     /// Unpacks a human-readable representation from `Grid`'s internal representation
@@ -300,14 +445,25 @@ impl Grid {
     }
 
     /// Parses a grid from the representation given by the `Display` implementation
-    pub fn from_display(s: &str) -> Option<Grid> {
-        let vec: Vec<u32> = s
+    pub fn from_display(s: &str) -> Result<Grid, GridError> {
+        let numbers = s
             .split(|c: char| !c.is_numeric())
-            .flat_map(|s| s.parse::<u32>())
-            .collect();
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+
+        if numbers.len() != 16 {
+            return Err(GridError::WrongTileCount {
+                expected: 16,
+                count: numbers.len(),
+            });
+        }
 
-        if vec.len() != 16 {
-            return None;
+        let mut vec = [0u32; 16];
+        for (index, number) in numbers.iter().enumerate() {
+            vec[index] = number.parse().map_err(|_| GridError::InvalidNumber {
+                row: index / 4,
+                column: index % 4,
+            })?;
         }
 
         let grid: [[u32; 4]; 4] = [
@@ -321,86 +477,179 @@ impl Grid {
     }
 
     fn from_log(grid: [[u8; 4]; 4]) -> Option<Grid> {
-        let mut rows = [Row::default(); 4];
-        for (x, &row) in grid.iter().enumerate() {
-            rows[x] = Row::pack(row).ok()?;
+        let mut result = Grid::default();
+        for (index, &tile) in grid.iter().flatten().enumerate() {
+            if tile > MAX_EXPONENT {
+                return None;
+            }
+            let nibble = cmp::min(tile, MAX_NIBBLE);
+            result.board |= u64::from(nibble) << (60 - index * 4);
+            result.ext |= u32::from(tile - nibble) << (30 - index * 2);
         }
-        Some(Grid::from_rows(rows))
+        Some(result)
     }
 
-    fn unpack_log(self) -> [[u8; 4]; 4] {
+    pub(crate) fn unpack_log(self) -> [[u8; 4]; 4] {
         let mut result = [[0; 4]; 4];
         for (x, row) in self.rows().iter().enumerate() {
             result[x] = row.unpack();
         }
+        if self.ext != 0 {
+            for (index, tile) in result.iter_mut().flatten().enumerate() {
+                *tile += ((self.ext >> (30 - index * 2)) & 0b11) as u8;
+            }
+        }
         result
     }
 
+    // The nibbles of all tiles, top left corner in the highest nibble
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    pub(crate) fn board(self) -> u64 {
+        self.board
+    }
+
+    // The two extra bits of the logarithm of every tile, top left corner in the highest bits
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    pub(crate) fn ext(self) -> u32 {
+        self.ext
+    }
+
+    /// Whether every tile fits a nibble, that is, there are no tiles bigger than 32768.
+    pub(crate) fn fits_nibbles(self) -> bool {
+        self.ext == 0
+    }
+
+    // The nibble of a tile, counting from the top left corner row by row. Tiles bigger than
+    // 32768 all have 15 in their nibble.
+    pub(crate) fn nibble(self, index: usize) -> u8 {
+        ((self.board >> (60 - index * 4)) & 0b1111) as u8
+    }
+
+    // The base 2 logarithm of a tile, counting from the top left corner row by row.
+    pub(crate) fn tile_log(self, index: usize) -> u8 {
+        let ext = (self.ext >> (30 - index * 2)) & 0b11;
+        self.nibble(index) + ext as u8
+    }
+
+    // The extension bits of every row, two per tile, top row first
+    pub(crate) fn row_extensions(self) -> [u8; 4] {
+        self.ext.to_be_bytes()
+    }
+
+    // Cached moves are only wrong for tiles that don't fit a nibble and for merging two 32768s.
+    // Checks the whole grid at once, so it's stricter than checking every row.
+    fn fits_cache(self) -> bool {
+        let x = self.board;
+        let maxed = x & (x >> 1) & (x >> 2) & (x >> 3) & 0x1111_1111_1111_1111;
+        self.fits_nibbles() && maxed & maxed.wrapping_sub(1) == 0
+    }
+
+    // Transposes only the nibbles, leaving out the extension
+    fn transpose_board(self) -> Grid {
+        Grid {
+            board: transpose_board(self.board),
+            ext: 0,
+        }
+    }
+
     pub(crate) fn rows(self) -> [Row; 4] {
-        let row1 = Row(((self.0 & 0xFFFF_0000_0000_0000) >> 48) as u16);
-        let row2 = Row(((self.0 & 0x0000_FFFF_0000_0000) >> 32) as u16);
-        let row3 = Row(((self.0 & 0x0000_0000_FFFF_0000) >> 16) as u16);
-        let row4 = Row((self.0 & 0x0000_0000_0000_FFFF) as u16);
+        let row1 = Row(((self.board & 0xFFFF_0000_0000_0000) >> 48) as u16);
+        let row2 = Row(((self.board & 0x0000_FFFF_0000_0000) >> 32) as u16);
+        let row3 = Row(((self.board & 0x0000_0000_FFFF_0000) >> 16) as u16);
+        let row4 = Row((self.board & 0x0000_0000_0000_FFFF) as u16);
         [row1, row2, row3, row4]
     }
 
     fn from_rows(rows: [Row; 4]) -> Self {
         let mut grid = Grid::default();
-        grid.0 |= u64::from(rows[0].0) << 48;
-        grid.0 |= u64::from(rows[1].0) << 32;
-        grid.0 |= u64::from(rows[2].0) << 16;
-        grid.0 |= u64::from(rows[3].0);
+        grid.board |= u64::from(rows[0].0) << 48;
+        grid.board |= u64::from(rows[1].0) << 32;
+        grid.board |= u64::from(rows[2].0) << 16;
+        grid.board |= u64::from(rows[3].0);
         grid
     }
 
     fn from_columns(columns: [Column; 4]) -> Self {
         let mut grid = Grid::default();
-        grid.0 |= columns[0].0 << 12;
-        grid.0 |= columns[1].0 << 8;
-        grid.0 |= columns[2].0 << 4;
-        grid.0 |= columns[3].0;
+        grid.board |= columns[0].0 << 12;
+        grid.board |= columns[1].0 << 8;
+        grid.board |= columns[2].0 << 4;
+        grid.board |= columns[3].0;
         grid
     }
 
     /// Creates a new `Grid` with a random tile (90% of times a `2`, 10% of times a `4`) added to a
     /// random empty tile on the grid.
     pub fn add_random_tile(self) -> Grid {
-        let mut rng = rand::thread_rng();
+        self.add_random_tile_with(&mut rand::thread_rng())
+    }
 
-        let mut grid = self.unpack_log();
-        let empty_tile_count = grid.iter().flatten().filter(|v| **v == 0).count();
-        let position = rng.gen_range(0, empty_tile_count);
+    /// Same as `add_random_tile`, but draws randomness from the supplied generator. Feeding it a
+    /// `GameRng` created from a seed makes the sequence of added tiles reproducible.
+    pub fn add_random_tile_with<R: Rng + ?Sized>(self, rng: &mut R) -> Grid {
+        SpawnRule::default().add_random_tile(self, rng)
+    }
 
-        let value = grid
-            .iter_mut()
-            .flatten()
-            .filter(|v| **v == 0)
-            .nth(position)
-            .unwrap();
+    /// Transposes the grid
+    pub fn transpose(self) -> Grid {
+        if !self.fits_nibbles() {
+            return Grid {
+                board: transpose_board(self.board),
+                ext: transpose_ext(self.ext),
+            };
+        }
 
-        *value = if rng.gen_bool(0.9) { 1 } else { 2 };
+        self.transpose_board()
+    }
 
-        Grid::from_log(grid).unwrap()
+    /// Mirrors the grid left to right
+    pub fn reflect_horizontally(self) -> Grid {
+        Grid {
+            board: reflect_board_horizontally(self.board),
+            ext: reflect_ext_horizontally(self.ext),
+        }
     }
 
-    /// Transposes the grid
-    pub fn transpose(self) -> Grid {
-        // Black magic to transpose a grid quickly.
-        let x = self.0;
-        let a1 = x & 0xF0F0_0F0F_F0F0_0F0F;
-        let a2 = x & 0x0000_F0F0_0000_F0F0;
-        let a3 = x & 0x0F0F_0000_0F0F_0000;
-        let a = a1 | (a2 << 12) | (a3 >> 12);
-        let b1 = a & 0xFF00_FF00_00FF_00FF;
-        let b2 = a & 0x00FF_00FF_0000_0000;
-        let b3 = a & 0x0000_0000_FF00_FF00;
-        let ret = b1 | (b2 >> 24) | (b3 << 24);
-        Grid(ret)
+    /// Mirrors the grid top to bottom
+    pub fn reflect_vertically(self) -> Grid {
+        Grid {
+            board: reflect_board_vertically(self.board),
+            ext: self.ext.swap_bytes(),
+        }
+    }
+
+    /// Picks one grid out of the 8 that are the same as this one up to rotation and reflection.
+    /// All 8 of them give the same result.
+    pub fn canonical(self) -> Grid {
+        self.canonical_with_symmetry().0
+    }
+
+    // Also returns which of the 8 symmetries turns `self` into the canonical grid
+    pub(crate) fn canonical_with_symmetry(self) -> (Grid, u8) {
+        let transposed = self.transpose();
+        let images = [
+            self,
+            self.reflect_horizontally(),
+            self.reflect_vertically(),
+            self.reflect_horizontally().reflect_vertically(),
+            transposed,
+            transposed.reflect_horizontally(),
+            transposed.reflect_vertically(),
+            transposed.reflect_horizontally().reflect_vertically(),
+        ];
+
+        let mut best = (self, 0);
+        for (symmetry, &image) in images.iter().enumerate().skip(1) {
+            if (image.board, image.ext) < (best.0.board, best.0.ext) {
+                best = (image, symmetry as u8);
+            }
+        }
+        best
     }
 
     /// Counts the number of empty tiles
     pub fn count_empty(self) -> usize {
-        let mut x = self.0;
+        let mut x = self.board;
         x |= (x >> 2) & 0x3333_3333_3333_3333;
         x |= x >> 1;
         x = (!x) & 0x1111_1111_1111_1111;
@@ -429,12 +678,224 @@ impl Grid {
     pub fn biggest_tile(self) -> u32 {
         self.unpack_human().iter().flatten().cloned().max().unwrap()
     }
+
+    /// The sum of all tiles on the grid. Moves keep it the same and every new tile adds to it,
+    /// so it never goes down over a game.
+    pub fn tile_sum(self) -> u32 {
+        self.unpack_log()
+            .iter()
+            .flatten()
+            .filter(|&&log| log != 0)
+            .map(|&log| 1 << log)
+            .sum()
+    }
+}
+
+// Black magic to transpose a grid quickly.
+fn transpose_board(x: u64) -> u64 {
+    let a1 = x & 0xF0F0_0F0F_F0F0_0F0F;
+    let a2 = x & 0x0000_F0F0_0000_F0F0;
+    let a3 = x & 0x0F0F_0000_0F0F_0000;
+    let a = a1 | (a2 << 12) | (a3 >> 12);
+    let b1 = a & 0xFF00_FF00_00FF_00FF;
+    let b2 = a & 0x00FF_00FF_0000_0000;
+    let b3 = a & 0x0000_0000_FF00_FF00;
+    b1 | (b2 >> 24) | (b3 << 24)
+}
+
+// Same as `transpose_board`, but for the two extension bits per cell. Spreads them out to a
+// nibble per cell and back.
+fn transpose_ext(x: u32) -> u32 {
+    let mut x = u64::from(x);
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    x = transpose_board(x);
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x >> 16)) & 0x0000_0000_FFFF_FFFF;
+    x as u32
+}
+
+// Reverses the nibbles within every row
+fn reflect_board_horizontally(x: u64) -> u64 {
+    let x = ((x & 0x00FF_00FF_00FF_00FF) << 8) | ((x >> 8) & 0x00FF_00FF_00FF_00FF);
+    ((x & 0x0F0F_0F0F_0F0F_0F0F) << 4) | ((x >> 4) & 0x0F0F_0F0F_0F0F_0F0F)
+}
+
+// Reverses the order of rows
+fn reflect_board_vertically(x: u64) -> u64 {
+    let x = x.rotate_left(32);
+    ((x & 0x0000_FFFF_0000_FFFF) << 16) | ((x >> 16) & 0x0000_FFFF_0000_FFFF)
+}
+
+// Same as `reflect_board_horizontally`, but for the two extension bits per cell
+fn reflect_ext_horizontally(x: u32) -> u32 {
+    let x = ((x & 0x0F0F_0F0F) << 4) | ((x >> 4) & 0x0F0F_0F0F);
+    ((x & 0x3333_3333) << 2) | ((x >> 2) & 0x3333_3333)
+}
+
+/// The outcome of a player move.
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Default)]
+pub struct MoveOutcome {
+    /// The grid after the move, before a random tile is added.
+    pub grid: Grid,
+    /// Points gained by the move, that is, the sum of the values of all merged tiles.
+    pub score: u32,
+}
+
+/// Where a tile went during a player move.
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
+pub struct TileMotion {
+    /// The cell the tile started on, counting row by row from the top left corner
+    pub from: usize,
+    /// The cell the tile ended up on. Same as `from` if the tile stayed put.
+    pub to: usize,
+    /// The value of the tile before the move
+    pub value: u32,
+    /// Whether the tile merged with another one on `to`. Both tiles of a merge are listed.
+    pub merged: bool,
+}
+
+/// A player move together with the motion of every tile, enough to animate it.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct TracedMove {
+    /// The grid after the move and the points gained by it
+    pub outcome: MoveOutcome,
+    /// Every tile on the grid before the move
+    pub tiles: Vec<TileMotion>,
+}
+
+/// The maximum number of different tiles a `SpawnRule` can spawn.
+pub const MAX_SPAWN_TILES: usize = 4;
+
+/// Decides which tiles appear on the grid after every move, and how often. The default is the
+/// original game's rule: 90% of times a `2`, 10% of times a `4`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpawnRule {
+    tiles: [(u8, f32); MAX_SPAWN_TILES],
+    len: usize,
+    // The probability of the first tile in double precision. Rules of two tiles spawn with a
+    // single coin toss at these odds, which is how the default rule has always drawn, so seeds
+    // keep giving the same games.
+    first_odds: f64,
+}
+
+impl Default for SpawnRule {
+    fn default() -> Self {
+        SpawnRule::with_odds(&[(2, 0.9), (4, 0.1)]).unwrap()
+    }
+}
+
+impl SpawnRule {
+    /// Creates a rule from pairs of tiles and their probabilities. Probabilities are normalized
+    /// so that they add up to 1. Returns `None` if there are no tiles or more than
+    /// `MAX_SPAWN_TILES` of them, if a tile fails to be a power of 2 between 2 and 32768, or if
+    /// a probability is not positive and finite.
+    pub fn new(tiles: &[(u32, f32)]) -> Option<SpawnRule> {
+        let mut odds = [(0, 0.0); MAX_SPAWN_TILES];
+        if tiles.len() > MAX_SPAWN_TILES {
+            return None;
+        }
+        for (&(tile, probability), entry) in tiles.iter().zip(odds.iter_mut()) {
+            *entry = (tile, f64::from(probability));
+        }
+        SpawnRule::with_odds(&odds[..tiles.len()])
+    }
+
+    fn with_odds(tiles: &[(u32, f64)]) -> Option<SpawnRule> {
+        if tiles.is_empty() || tiles.len() > MAX_SPAWN_TILES {
+            return None;
+        }
+
+        let total = tiles
+            .iter()
+            .map(|&(_, probability)| probability)
+            .sum::<f64>();
+        if !total.is_finite() {
+            return None;
+        }
+        let mut rule = SpawnRule {
+            tiles: [(0, 0.0); MAX_SPAWN_TILES],
+            len: tiles.len(),
+            first_odds: tiles[0].1 / total,
+        };
+        for (&(tile, probability), entry) in tiles.iter().zip(rule.tiles.iter_mut()) {
+            let log = to_log(tile).filter(|&log| log > 0 && log <= MAX_NIBBLE)?;
+            if !probability.is_finite() || probability <= 0.0 {
+                return None;
+            }
+            *entry = (log, (probability / total) as f32);
+        }
+        Some(rule)
+    }
+
+    /// A rule that always spawns the same tile.
+    pub fn always(tile: u32) -> Option<SpawnRule> {
+        SpawnRule::new(&[(tile, 1.0)])
+    }
+
+    /// The tiles this rule spawns together with their probabilities.
+    pub fn tiles(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        self.log_tiles()
+            .iter()
+            .map(|&(log, probability)| (1 << log, probability))
+    }
+
+    pub(crate) fn log_tiles(&self) -> &[(u8, f32)] {
+        &self.tiles[..self.len]
+    }
+
+    /// Creates a new `Grid` with a tile chosen by this rule added to a random empty tile on
+    /// the grid.
+    pub fn add_random_tile<R: Rng + ?Sized>(&self, grid: Grid, rng: &mut R) -> Grid {
+        let mut grid = grid.unpack_log();
+        let empty_tile_count = grid.iter().flatten().filter(|v| **v == 0).count();
+        let position = rng.gen_range(0, empty_tile_count);
+
+        let value = grid
+            .iter_mut()
+            .flatten()
+            .filter(|v| **v == 0)
+            .nth(position)
+            .unwrap();
+
+        *value = self.choose_log(rng);
+
+        Grid::from_log(grid).unwrap()
+    }
+
+    // Picks the base 2 logarithm of a tile to spawn
+    pub(crate) fn choose_log<R: Rng + ?Sized>(&self, rng: &mut R) -> u8 {
+        if self.len == 2 {
+            let (first, _) = self.tiles[0];
+            let (second, _) = self.tiles[1];
+            return if rng.gen_bool(self.first_odds) {
+                first
+            } else {
+                second
+            };
+        }
+
+        let mut roll = rng.gen::<f32>();
+        let (last, _) = self.log_tiles()[self.len - 1];
+        self.log_tiles()
+            .iter()
+            .find(|&&(_, probability)| {
+                roll -= probability;
+                roll < 0.0
+            })
+            .map_or(last, |&(log, _)| log)
+    }
 }
 
 /// Game engine capable of manipulating game state
 #[derive(Debug, Copy, Clone)]
 pub struct GameEngine {
     cache: Cache,
+    spawn_rule: SpawnRule,
 }
 
 impl Default for GameEngine {
@@ -445,7 +906,9 @@ impl Default for GameEngine {
                 right: &CACHE_RIGHT,
                 up: &CACHE_UP,
                 down: &CACHE_DOWN,
+                score: &CACHE_SCORE,
             },
+            spawn_rule: SpawnRule::default(),
         }
     }
 }
@@ -456,6 +919,24 @@ impl GameEngine {
         Self::default()
     }
 
+    /// Initializes the game engine with a custom rule for spawning tiles
+    pub fn with_spawn_rule(spawn_rule: SpawnRule) -> Self {
+        Self {
+            spawn_rule,
+            ..Self::default()
+        }
+    }
+
+    /// The rule this engine uses to spawn tiles
+    pub fn spawn_rule(self) -> SpawnRule {
+        self.spawn_rule
+    }
+
+    /// Creates a new `Grid` with a random tile added according to the engine's `SpawnRule`.
+    pub fn add_random_tile<R: Rng + ?Sized>(self, grid: Grid, rng: &mut R) -> Grid {
+        self.spawn_rule.add_random_tile(grid, rng)
+    }
+
     /// Find out if the game is lost at the game state represented by the grid
     pub fn game_over(self, grid: Grid) -> bool {
         MOVES
@@ -474,6 +955,11 @@ impl GameEngine {
         RandomMoves::new(grid, 2)
     }
 
+    /// Returns all possible moves with a new tile, given as the base 2 logarithm of its value
+    pub(crate) fn random_moves_with(self, grid: Grid, log: u8) -> impl Iterator<Item = Grid> {
+        RandomMoves::new(grid, log)
+    }
+
     /// Returns all possible player moves
     pub fn player_moves(self, grid: Grid) -> impl Iterator<Item = (Move, Grid)> {
         MOVES.iter().filter_map(move |&m| {
@@ -487,8 +973,11 @@ impl GameEngine {
     }
 
     /// Returns a `Grid` that would result from making a certain `Move` in the current state.
-    /// Limitation: if two 32768 tiles would be merged as a result of the move, the resulting tile becomes a 32768 instead.
     pub fn make_move(self, grid: Grid, mv: Move) -> Grid {
+        if !grid.fits_cache() {
+            return self.make_move_by_row(grid, mv).grid;
+        }
+
         match mv {
             Move::Left => self.move_left(grid),
             Move::Right => self.move_right(grid),
@@ -497,6 +986,104 @@ impl GameEngine {
         }
     }
 
+    /// Makes a `Move` like `make_move` does, but also reports the points gained by it.
+    pub fn make_scored_move(self, grid: Grid, mv: Move) -> MoveOutcome {
+        if !grid.fits_cache() {
+            return self.make_move_by_row(grid, mv);
+        }
+
+        MoveOutcome {
+            grid: self.make_move(grid, mv),
+            score: self.move_score(grid, mv),
+        }
+    }
+
+    /// Makes a `Move` like `make_scored_move` does, but also reports where every tile went.
+    /// Much slower than `make_move`, so it's meant for showing moves rather than searching.
+    pub fn make_traced_move(self, grid: Grid, mv: Move) -> TracedMove {
+        let tiles = grid.unpack_log();
+        let mut motions = Vec::new();
+        for line in 0..4 {
+            // The cells of a row or column, starting from the side the tiles move to
+            let mut cells = match mv {
+                Move::Left | Move::Right => [line * 4, line * 4 + 1, line * 4 + 2, line * 4 + 3],
+                Move::Up | Move::Down => [line, line + 4, line + 8, line + 12],
+            };
+            if mv == Move::Right || mv == Move::Down {
+                cells.reverse();
+            }
+            trace_line(&tiles, cells, &mut motions);
+        }
+        TracedMove {
+            outcome: self.make_scored_move(grid, mv),
+            tiles: motions,
+        }
+    }
+
+    /// The points a `Move` would gain in the current state.
+    pub fn move_score(self, grid: Grid, mv: Move) -> u32 {
+        if !grid.fits_cache() {
+            return self.make_move_by_row(grid, mv).score;
+        }
+
+        let rows = match mv {
+            Move::Left | Move::Right => grid.rows(),
+            Move::Up | Move::Down => grid.transpose_board().rows(),
+        };
+        rows.iter().map(|&row| self.cache.lookup_score(row)).sum()
+    }
+
+    // Moves one row or column at a time. Looks up the ones that fit the cache, and slides only
+    // those with a tile bigger than 32768, or two 32768s, by hand.
+    #[cold]
+    #[inline(never)]
+    fn make_move_by_row(self, grid: Grid, mv: Move) -> MoveOutcome {
+        let lines = match mv {
+            Move::Left | Move::Right => grid,
+            Move::Up | Move::Down => grid.transpose(),
+        };
+        let reversed = mv == Move::Right || mv == Move::Down;
+
+        let mut moved = Grid::default();
+        let mut score = 0;
+        for (index, (&row, &ext)) in lines
+            .rows()
+            .iter()
+            .zip(lines.row_extensions().iter())
+            .enumerate()
+        {
+            let (row, ext) = if ext == 0 && row.fits_cache() {
+                score += self.cache.lookup_score(row);
+                if reversed {
+                    (self.cache.lookup_right(row), 0)
+                } else {
+                    (self.cache.lookup_left(row), 0)
+                }
+            } else {
+                let mut tiles = row.unpack_with_ext(ext);
+                if reversed {
+                    tiles.reverse();
+                }
+                let (mut tiles, row_score) = slide_left(tiles);
+                if reversed {
+                    tiles.reverse();
+                }
+                score += row_score;
+                Row::pack_with_ext(tiles)
+            };
+            moved.board |= u64::from(row.0) << (48 - index * 16);
+            moved.ext |= u32::from(ext) << (24 - index * 8);
+        }
+
+        MoveOutcome {
+            grid: match mv {
+                Move::Left | Move::Right => moved,
+                Move::Up | Move::Down => moved.transpose(),
+            },
+            score,
+        }
+    }
+
     fn move_left(self, grid: Grid) -> Grid {
         let rows = grid.rows();
         let row0 = self.cache.lookup_left(rows[0]);
@@ -516,7 +1103,7 @@ impl GameEngine {
     }
 
     fn move_up(self, grid: Grid) -> Grid {
-        let rows = grid.transpose().rows();
+        let rows = grid.transpose_board().rows();
         let col0 = self.cache.lookup_up(rows[0]);
         let col1 = self.cache.lookup_up(rows[1]);
         let col2 = self.cache.lookup_up(rows[2]);
@@ -525,7 +1112,7 @@ impl GameEngine {
     }
 
     fn move_down(self, grid: Grid) -> Grid {
-        let rows = grid.transpose().rows();
+        let rows = grid.transpose_board().rows();
         let col0 = self.cache.lookup_down(rows[0]);
         let col1 = self.cache.lookup_down(rows[1]);
         let col2 = self.cache.lookup_down(rows[2]);
@@ -534,6 +1121,47 @@ impl GameEngine {
     }
 }
 
+// Follows the tiles of a row or column the same way `slide_left` moves them
+fn trace_line(tiles: &[[u8; 4]; 4], cells: [usize; 4], motions: &mut Vec<TileMotion>) {
+    let motion = |from: usize, to: usize, merged: bool| TileMotion {
+        from: cells[from],
+        to: cells[to],
+        value: 1 << tiles[cells[from] / 4][cells[from] % 4],
+        merged,
+    };
+    let mut last: Option<(usize, u8)> = None;
+    let mut last_index = 0;
+
+    for (position, &cell) in cells.iter().enumerate() {
+        let tile = tiles[cell / 4][cell % 4];
+        if tile == 0 {
+            continue;
+        }
+
+        match last {
+            None => {
+                last = Some((position, tile));
+                continue;
+            }
+            Some((last_position, last_tile)) if last_tile == tile => {
+                motions.push(motion(last_position, last_index, true));
+                motions.push(motion(position, last_index, true));
+                last = None;
+            }
+            Some((last_position, _)) => {
+                motions.push(motion(last_position, last_index, false));
+                last = Some((position, tile));
+            }
+        }
+
+        last_index += 1;
+    }
+
+    if let Some((last_position, _)) = last {
+        motions.push(motion(last_position, last_index, false));
+    }
+}
+
 struct RandomMoves {
     grid: Grid,
     index: u8,
@@ -560,8 +1188,11 @@ impl Iterator for RandomMoves {
             }
             self.index -= 1;
             let mask = 0b1111u64 << (self.index * 4);
-            if (self.grid.0 & mask) == 0 {
-                let grid = Grid(self.grid.0 | u64::from(self.val) << (self.index * 4));
+            if (self.grid.board & mask) == 0 {
+                let grid = Grid {
+                    board: self.grid.board | u64::from(self.val) << (self.index * 4),
+                    ext: self.grid.ext,
+                };
                 return Some(grid);
             }
         }
@@ -572,6 +1203,49 @@ impl Iterator for RandomMoves {
 mod tests {
     use super::*;
 
+    fn transpose_log(grid: [[u8; 4]; 4]) -> [[u8; 4]; 4] {
+        let mut result = [[0; 4]; 4];
+        for (x, row) in grid.iter().enumerate() {
+            for (y, &tile) in row.iter().enumerate() {
+                result[y][x] = tile;
+            }
+        }
+        result
+    }
+
+    // Moves tiles one row at a time without the help of the cache. Slow, but handles every tile.
+    fn make_move_uncached(grid: Grid, mv: Move) -> MoveOutcome {
+        let tiles = match mv {
+            Move::Left | Move::Right => grid.unpack_log(),
+            Move::Up | Move::Down => transpose_log(grid.unpack_log()),
+        };
+
+        let mut moved = [[0; 4]; 4];
+        let mut score = 0;
+        for (from_row, to_row) in tiles.iter().zip(moved.iter_mut()) {
+            let mut from_row = *from_row;
+            if mv == Move::Right || mv == Move::Down {
+                from_row.reverse();
+            }
+            let (mut row, row_score) = slide_left(from_row);
+            if mv == Move::Right || mv == Move::Down {
+                row.reverse();
+            }
+            *to_row = row;
+            score += row_score;
+        }
+
+        let moved = match mv {
+            Move::Left | Move::Right => moved,
+            Move::Up | Move::Down => transpose_log(moved),
+        };
+
+        MoveOutcome {
+            grid: Grid::from_log(moved).unwrap(),
+            score,
+        }
+    }
+
     #[test]
     fn can_create_empty_grid() {
         let expected =
@@ -582,6 +1256,80 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn can_sum_tiles() {
+        let grid = Grid::from_human([
+            [0, 2, 4, 8],
+            [0, 0, 0, 0],
+            [65536, 0, 0, 2],
+            [0, 0, 0, 1024],
+        ])
+        .unwrap();
+
+        assert_eq!(grid.tile_sum(), 65536 + 1024 + 16);
+        assert_eq!(Grid::default().tile_sum(), 0);
+    }
+
+    #[test]
+    fn can_reflect_grid() {
+        let grid = Grid::from_human([
+            [0, 2, 4, 8],
+            [16, 0, 0, 0],
+            [65536, 0, 0, 2],
+            [0, 0, 131072, 1024],
+        ])
+        .unwrap();
+        let horizontal = Grid::from_human([
+            [8, 4, 2, 0],
+            [0, 0, 0, 16],
+            [2, 0, 0, 65536],
+            [1024, 131072, 0, 0],
+        ])
+        .unwrap();
+        let vertical = Grid::from_human([
+            [0, 0, 131072, 1024],
+            [65536, 0, 0, 2],
+            [16, 0, 0, 0],
+            [0, 2, 4, 8],
+        ])
+        .unwrap();
+
+        assert_eq!(grid.reflect_horizontally(), horizontal);
+        assert_eq!(grid.reflect_vertically(), vertical);
+        assert_eq!(grid.reflect_horizontally().reflect_horizontally(), grid);
+        assert_eq!(grid.reflect_vertically().reflect_vertically(), grid);
+    }
+
+    #[test]
+    fn symmetric_grids_have_same_canonical_grid() {
+        let grid = Grid::from_human([
+            [0, 2, 4, 8],
+            [16, 0, 0, 0],
+            [65536, 0, 0, 2],
+            [0, 0, 131072, 1024],
+        ])
+        .unwrap();
+        let transposed = grid.transpose();
+        let images = [
+            grid,
+            grid.reflect_horizontally(),
+            grid.reflect_vertically(),
+            grid.reflect_horizontally().reflect_vertically(),
+            transposed,
+            transposed.reflect_horizontally(),
+            transposed.reflect_vertically(),
+            transposed.reflect_horizontally().reflect_vertically(),
+        ];
+
+        let canonical = grid.canonical();
+
+        assert!(images.contains(&canonical));
+        for image in &images {
+            assert_eq!(image.canonical(), canonical);
+        }
+        assert_ne!(Grid::default().add_random_tile().canonical(), canonical);
+    }
+
     #[test]
     fn can_create_grid_from_human_input() {
         let human = [
@@ -593,16 +1341,63 @@ mod tests {
 
         let actual = Grid::from_human(human);
 
-        assert!(actual.is_some());
+        assert!(actual.is_ok());
         assert_eq!(human, actual.unwrap().unpack_human());
     }
 
     #[test]
-    fn can_return_none_on_invalid_input() {
+    fn can_create_grid_with_tiles_beyond_32768() {
+        let human = [
+            [0, 2, 4, 8],
+            [16, 32, 64, 128],
+            [256, 512, 1024, 2048],
+            [32768, 65536, 131_072, 262_144],
+        ];
+
+        let actual = Grid::from_human(human);
+
+        assert!(actual.is_ok());
+        assert_eq!(human, actual.unwrap().unpack_human());
+        assert_eq!(262_144, actual.unwrap().biggest_tile());
+    }
+
+    #[test]
+    fn can_return_error_on_too_big_tile() {
+        let result = Grid::from_human([[0; 4], [0, 0, 524_288, 0], [0; 4], [0; 4]]);
+
+        assert_eq!(
+            result,
+            Err(GridError::InvalidTile {
+                row: 1,
+                column: 2,
+                tile: 524_288
+            })
+        );
+    }
+
+    #[test]
+    fn can_return_error_on_invalid_input() {
         let result =
             Grid::from_human([[0, 1, 2, 3], [4, 5, 6, 7], [8, 9, 10, 11], [12, 13, 14, 15]]);
+        // Rounds to a power of 2, but isn't one
+        let close = Grid::from_human([[0; 4], [0; 4], [0; 4], [0, 0, 0, 5]]);
 
-        assert!(result.is_none());
+        assert_eq!(
+            result,
+            Err(GridError::InvalidTile {
+                row: 0,
+                column: 3,
+                tile: 3
+            })
+        );
+        assert_eq!(
+            close,
+            Err(GridError::InvalidTile {
+                row: 3,
+                column: 3,
+                tile: 5
+            })
+        );
     }
 
     #[test]
@@ -624,6 +1419,94 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_add_random_tiles_reproducibly() {
+        for seed in 0..100 {
+            let mut rng1 = seeded_rng(seed);
+            let mut rng2 = seeded_rng(seed);
+            let mut grid1 = Grid::default();
+            let mut grid2 = Grid::default();
+            for _ in 0..16 {
+                grid1 = grid1.add_random_tile_with(&mut rng1);
+                grid2 = grid2.add_random_tile_with(&mut rng2);
+                assert_eq!(grid1, grid2);
+            }
+        }
+    }
+
+    #[test]
+    fn can_create_spawn_rules() {
+        assert_eq!(
+            vec![(2, 0.9), (4, 0.1)],
+            SpawnRule::default().tiles().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(2, 0.75), (4, 0.25)],
+            SpawnRule::new(&[(2, 3.0), (4, 1.0)])
+                .unwrap()
+                .tiles()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(8, 1.0)],
+            SpawnRule::always(8).unwrap().tiles().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn default_rule_spawns_the_same_tiles_for_the_same_seed() {
+        // How tiles were drawn before there were spawn rules
+        fn add_random_tile(grid: Grid, rng: &mut GameRng) -> Grid {
+            let mut tiles = grid.unpack_log();
+            let empty_tile_count = tiles.iter().flatten().filter(|v| **v == 0).count();
+            let position = rng.gen_range(0, empty_tile_count);
+            let value = tiles
+                .iter_mut()
+                .flatten()
+                .filter(|v| **v == 0)
+                .nth(position)
+                .unwrap();
+            *value = if rng.gen_bool(0.9) { 1 } else { 2 };
+            Grid::from_log(tiles).unwrap()
+        }
+
+        for seed in 0..100 {
+            let mut rng1 = seeded_rng(seed);
+            let mut rng2 = seeded_rng(seed);
+            let mut grid1 = Grid::default();
+            let mut grid2 = Grid::default();
+            for _ in 0..16 {
+                grid1 = grid1.add_random_tile_with(&mut rng1);
+                grid2 = add_random_tile(grid2, &mut rng2);
+                assert_eq!(grid1, grid2);
+            }
+        }
+    }
+
+    #[test]
+    fn can_return_none_on_invalid_spawn_rule() {
+        assert!(SpawnRule::new(&[]).is_none());
+        assert!(SpawnRule::new(&[(2, 0.2), (4, 0.2), (8, 0.2), (16, 0.2), (32, 0.2)]).is_none());
+        assert!(SpawnRule::new(&[(2, 0.5), (3, 0.5)]).is_none());
+        assert!(SpawnRule::new(&[(2, 1.0), (4, 0.0)]).is_none());
+        assert!(SpawnRule::new(&[(2, 1.0), (4, f32::INFINITY)]).is_none());
+        assert!(SpawnRule::new(&[(2, f32::MAX), (4, f32::MAX)]).is_some());
+        assert!(SpawnRule::always(1).is_none());
+        assert!(SpawnRule::always(65536).is_none());
+    }
+
+    #[test]
+    fn can_add_random_tile_with_spawn_rule() {
+        let game_engine = GameEngine::with_spawn_rule(SpawnRule::always(8).unwrap());
+        let mut rng = seeded_rng(0);
+        let mut grid = Grid::default();
+        for _ in 0..16 {
+            grid = game_engine.add_random_tile(grid, &mut rng);
+        }
+
+        assert_eq!([[8; 4]; 4], grid.unpack_human());
+    }
+
     #[test]
     fn can_to_string() {
         let grid = Grid::from_human([
@@ -697,6 +1580,245 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn can_score_moves() {
+        let game_engine = GameEngine::new();
+        let grid =
+            Grid::from_human([[2, 2, 4, 4], [0, 2, 2, 0], [0, 2, 2, 2], [2, 0, 0, 2]]).unwrap();
+
+        assert_eq!(24, game_engine.move_score(grid, Move::Left));
+        assert_eq!(24, game_engine.move_score(grid, Move::Right));
+        assert_eq!(16, game_engine.move_score(grid, Move::Up));
+        assert_eq!(16, game_engine.move_score(grid, Move::Down));
+    }
+
+    #[test]
+    fn can_make_scored_move() {
+        let game_engine = GameEngine::new();
+        let grid =
+            Grid::from_human([[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [8, 8, 16, 16]]).unwrap();
+        let expected = MoveOutcome {
+            grid: Grid::from_human([[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [16, 32, 0, 0]])
+                .unwrap(),
+            score: 48,
+        };
+
+        let actual = game_engine.make_scored_move(grid, Move::Left);
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn can_trace_move() {
+        let game_engine = GameEngine::new();
+        let grid =
+            Grid::from_human([[0, 0, 0, 0], [0, 0, 0, 0], [0, 4, 0, 0], [8, 8, 16, 2]]).unwrap();
+
+        let traced = game_engine.make_traced_move(grid, Move::Right);
+
+        assert_eq!(
+            traced.outcome,
+            game_engine.make_scored_move(grid, Move::Right)
+        );
+        let motion = |from, to, value, merged| TileMotion {
+            from,
+            to,
+            value,
+            merged,
+        };
+        assert_eq!(
+            traced.tiles,
+            vec![
+                motion(9, 11, 4, false),
+                motion(15, 15, 2, false),
+                motion(14, 14, 16, false),
+                motion(13, 13, 8, true),
+                motion(12, 13, 8, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn traced_moves_match_moves() {
+        let game_engine = GameEngine::new();
+        let mut rng = seeded_rng(7);
+        for _ in 0..200 {
+            let mut grid = Grid::default();
+            for _ in 0..rng.gen_range(1, 14) {
+                grid = grid.add_random_tile_with(&mut rng);
+            }
+            for &mv in &MOVES {
+                let traced = game_engine.make_traced_move(grid, mv);
+
+                let mut tiles = [0u32; 16];
+                for tile in &traced.tiles {
+                    tiles[tile.to] += tile.value;
+                }
+                let after = traced.outcome.grid.unpack_human();
+                assert_eq!(&tiles[..], after.concat().as_slice());
+                assert_eq!(traced.tiles.len(), 16 - grid.count_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn cached_moves_match_uncached_moves() {
+        let game_engine = GameEngine::new();
+        let mut rng = seeded_rng(0);
+        for _ in 0..1000 {
+            let mut tiles = [[0u8; 4]; 4];
+            for tile in tiles.iter_mut().flatten() {
+                *tile = rng.gen_range(0, 16);
+            }
+            let grid = Grid::from_log(tiles).unwrap();
+
+            for &mv in &MOVES {
+                assert_eq!(
+                    make_move_uncached(grid, mv),
+                    game_engine.make_scored_move(grid, mv)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn moves_with_big_tiles_match_uncached_moves() {
+        let game_engine = GameEngine::new();
+        let mut rng = seeded_rng(1);
+        for _ in 0..1000 {
+            // Mostly small tiles, so that most rows still fit the cache
+            let mut tiles = [[0u8; 4]; 4];
+            for tile in tiles.iter_mut().flatten() {
+                *tile = match rng.gen_range(0, 10) {
+                    0 => rng.gen_range(MAX_NIBBLE, MAX_EXPONENT),
+                    _ => rng.gen_range(0, 12),
+                };
+            }
+            let grid = Grid::from_log(tiles).unwrap();
+
+            assert_eq!(
+                grid.transpose(),
+                Grid::from_log(transpose_log(tiles)).unwrap()
+            );
+            for &mv in &MOVES {
+                let expected = make_move_uncached(grid, mv);
+                assert_eq!(expected, game_engine.make_scored_move(grid, mv));
+                assert_eq!(expected.grid, game_engine.make_move(grid, mv));
+                assert_eq!(expected.score, game_engine.move_score(grid, mv));
+            }
+        }
+    }
+
+    #[test]
+    fn can_merge_32768s() {
+        let game_engine = GameEngine::new();
+        let grid =
+            Grid::from_human([[32768, 32768, 0, 0], [32768, 0, 0, 0], [0; 4], [0; 4]]).unwrap();
+
+        let left = game_engine.make_scored_move(grid, Move::Left);
+        let up = game_engine.make_scored_move(grid, Move::Up);
+
+        assert_eq!(
+            MoveOutcome {
+                grid: Grid::from_human([[65536, 0, 0, 0], [32768, 0, 0, 0], [0; 4], [0; 4]])
+                    .unwrap(),
+                score: 65536,
+            },
+            left
+        );
+        assert_eq!(
+            MoveOutcome {
+                grid: Grid::from_human([[65536, 32768, 0, 0], [0; 4], [0; 4], [0; 4]]).unwrap(),
+                score: 65536,
+            },
+            up
+        );
+    }
+
+    #[test]
+    fn can_move_tiles_beyond_32768() {
+        let game_engine = GameEngine::new();
+        let grid = Grid::from_human([
+            [65536, 65536, 32768, 32768],
+            [32768, 65536, 0, 2],
+            [0, 0, 0, 2],
+            [0, 0, 131_072, 0],
+        ])
+        .unwrap();
+
+        let right = game_engine.make_scored_move(grid, Move::Right);
+        let down = game_engine.make_scored_move(grid, Move::Down);
+
+        assert_eq!(
+            MoveOutcome {
+                grid: Grid::from_human([
+                    [0, 0, 131_072, 65536],
+                    [0, 32768, 65536, 2],
+                    [0, 0, 0, 2],
+                    [0, 0, 0, 131_072],
+                ])
+                .unwrap(),
+                score: 196_608,
+            },
+            right
+        );
+        assert_eq!(
+            MoveOutcome {
+                grid: Grid::from_human([
+                    [0, 0, 0, 0],
+                    [0, 0, 0, 0],
+                    [65536, 0, 32768, 32768],
+                    [32768, 131_072, 131_072, 4],
+                ])
+                .unwrap(),
+                score: 131_076,
+            },
+            down
+        );
+    }
+
+    #[test]
+    fn can_detect_terminal_state_with_tiles_beyond_32768() {
+        let game_engine = GameEngine::new();
+        let grid = Grid::from_human([
+            [32768, 65536, 32768, 65536],
+            [65536, 32768, 65536, 32768],
+            [2, 4, 2, 4],
+            [4, 2, 4, 2],
+        ])
+        .unwrap();
+
+        assert!(game_engine.game_over(grid));
+    }
+
+    #[test]
+    fn can_transpose_tiles_beyond_32768() {
+        let grid =
+            Grid::from_human([[0, 65536, 0, 0], [0, 0, 0, 131_072], [0; 4], [0; 4]]).unwrap();
+        let expected =
+            Grid::from_human([[0, 0, 0, 0], [65536, 0, 0, 0], [0; 4], [0, 131_072, 0, 0]]).unwrap();
+
+        assert_eq!(expected, grid.transpose());
+    }
+
+    #[test]
+    fn can_add_random_tiles_next_to_tiles_beyond_32768() {
+        let game_engine = GameEngine::new();
+        let grid = Grid::from_human([[65536, 8, 8, 8], [8, 8, 0, 8], [8; 4], [8; 4]]).unwrap();
+
+        let expected = Grid::from_human([[65536, 8, 8, 8], [8, 8, 2, 8], [8; 4], [8; 4]]).unwrap();
+
+        assert_eq!(
+            vec![expected],
+            game_engine.random_moves_with2(grid).collect::<Vec<_>>()
+        );
+
+        let added = grid.add_random_tile_with(&mut seeded_rng(0));
+
+        assert_eq!(65536, added.unpack_human()[0][0]);
+        assert_eq!(0, added.count_empty());
+    }
+
     #[test]
     fn can_possible_grids_with2() {
         let game_engine = GameEngine::new();
@@ -836,6 +1958,31 @@ mod tests {
         assert_eq!(grid, expected);
     }
 
+    #[test]
+    fn can_parse_tiles_beyond_32768_from_display() {
+        let grid = Grid::from_human([[131_072, 65536, 32768, 16384], [1, 2, 4, 8], [0; 4], [0; 4]])
+            .unwrap();
+
+        let back = Grid::from_display(&grid.to_string()).unwrap();
+
+        assert_eq!(grid, back);
+    }
+
+    #[test]
+    fn from_display_rejects_garbage() {
+        assert_eq!(
+            Grid::from_display("2 4 8"),
+            Err(GridError::WrongTileCount {
+                expected: 16,
+                count: 3
+            })
+        );
+        assert_eq!(
+            Grid::from_display("0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 99999999999"),
+            Err(GridError::InvalidNumber { row: 3, column: 3 })
+        );
+    }
+
     #[test]
     fn can_parse_from_display() {
         let grid = Grid::from_human([
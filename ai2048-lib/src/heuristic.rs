@@ -2,32 +2,239 @@
 
 use crate::game_logic::{Grid, Row};
 use lazy_static::lazy_static;
-use std::{cmp, i32, u16};
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
 
-/// Heuristic for evaluating grids
-#[derive(Debug, Clone, Copy)]
-pub struct Heuristic {
-    cache: &'static [f32],
+/// Evaluates positions for the searcher.
+pub trait Heuristic: Sync {
+    /// Evaluates a grid and spits out a representation of how good it is. Bigger is better.
+    fn eval(&self, grid: Grid) -> f32;
+
+    /// Whether a grid evaluates the same as all of its rotations and reflections. If it does, the
+    /// searcher can share cache entries between them.
+    fn is_symmetric(&self) -> bool {
+        false
+    }
+
+    /// Splits the evaluation of a grid into the parts its features contribute, for heuristics
+    /// made of features that can be told apart.
+    fn features(&self, _grid: Grid) -> Option<Features> {
+        None
+    }
+}
+
+/// Lets searchers share a heuristic that is expensive to clone, such as a big n-tuple network.
+impl<H: Heuristic + Send + ?Sized> Heuristic for Arc<H> {
+    fn eval(&self, grid: Grid) -> f32 {
+        (**self).eval(grid)
+    }
+
+    fn is_symmetric(&self) -> bool {
+        (**self).is_symmetric()
+    }
+
+    fn features(&self, grid: Grid) -> Option<Features> {
+        (**self).features(grid)
+    }
+}
+
+/// Weights of the features `RowHeuristic` evaluates for every row and column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeuristicWeights {
+    /// Awarded just for not having lost yet
+    pub not_lost: f32,
+    /// Penalty for tiles that don't increase or decrease monotonically
+    pub monotonicity: f32,
+    /// Reward for every empty tile
+    pub empty: f32,
+    /// Reward for every pair of adjacent equal tiles
+    pub adjacent: f32,
+    /// Penalty for the sum of tiles, which keeps the search from delaying merges
+    pub sum: f32,
 }
 
-impl Default for Heuristic {
+impl Default for HeuristicWeights {
     fn default() -> Self {
-        Self { cache: &CACHE }
+        HeuristicWeights {
+            not_lost: 200_000.0,
+            monotonicity: 47.0,
+            empty: 270.0,
+            adjacent: 700.0,
+            sum: 11.0,
+        }
+    }
+}
+
+impl fmt::Display for HeuristicWeights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, {}, {}",
+            self.not_lost, self.monotonicity, self.empty, self.adjacent, self.sum
+        )
+    }
+}
+
+impl HeuristicWeights {
+    /// Parses weights from the representation given by the `Display` implementation, that is,
+    /// five numbers in the order of declaration separated by commas or whitespace. Infinite and
+    /// NaN weights are rejected, as they make evaluations impossible to compare.
+    pub fn from_display(s: &str) -> Option<HeuristicWeights> {
+        let vec = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f32>().ok().filter(|weight| weight.is_finite()))
+            .collect::<Option<Vec<_>>>()?;
+
+        if vec.len() != 5 {
+            return None;
+        }
+
+        Some(HeuristicWeights {
+            not_lost: vec[0],
+            monotonicity: vec[1],
+            empty: vec[2],
+            adjacent: vec[3],
+            sum: vec[4],
+        })
+    }
+
+    /// Reads weights from a file written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<HeuristicWeights> {
+        let s = fs::read_to_string(path)?;
+        HeuristicWeights::from_display(&s).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected five numbers separated by commas",
+            )
+        })
+    }
+
+    /// Writes weights to a file in the format of the `Display` implementation.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, format!("{}\n", self))
+    }
+}
+
+/// What every feature of `RowHeuristic` contributes to the evaluation of a grid, summed over all
+/// rows and columns and multiplied by its weight.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Features {
+    /// Awarded just for not having lost yet
+    pub not_lost: f32,
+    /// Penalty for tiles that don't increase or decrease monotonically
+    pub monotonicity: f32,
+    /// Reward for empty tiles
+    pub empty: f32,
+    /// Reward for pairs of adjacent equal tiles
+    pub adjacent: f32,
+    /// Penalty for the sum of tiles
+    pub sum: f32,
+}
+
+impl Features {
+    /// The evaluation the features add up to
+    pub fn total(&self) -> f32 {
+        self.not_lost + self.monotonicity + self.empty + self.adjacent + self.sum
+    }
+}
+
+/// The default heuristic. Evaluates every row and column separately using a precomputed table.
+#[derive(Debug, Clone)]
+pub struct RowHeuristic {
+    cache: Arc<[f32]>,
+    weights: HeuristicWeights,
+}
+
+impl Default for RowHeuristic {
+    fn default() -> Self {
+        Self {
+            cache: CACHE.clone(),
+            weights: HeuristicWeights::default(),
+        }
+    }
+}
+
+impl Heuristic for RowHeuristic {
+    fn eval(&self, grid: Grid) -> f32 {
+        if !grid.fits_nibbles() {
+            return self.eval_by_row(grid);
+        }
+
+        grid.rows()
+            .iter()
+            .chain(grid.transpose().rows().iter())
+            .map(|&r| self.eval_row(r))
+            .sum()
+    }
+
+    /// Rows and columns are evaluated the same way, and every feature of a row is the same
+    /// when the row is read backwards.
+    fn is_symmetric(&self) -> bool {
+        true
+    }
+
+    fn features(&self, grid: Grid) -> Option<Features> {
+        let weights = &self.weights;
+        let mut features = Features::default();
+        for &row in grid
+            .unpack_log()
+            .iter()
+            .chain(grid.transpose().unpack_log().iter())
+        {
+            features.not_lost += weights.not_lost;
+            features.monotonicity += monotonicity_row(&row) * weights.monotonicity;
+            features.empty += empty_tile_count_row(&row) * weights.empty;
+            features.adjacent += adjacent_row(&row) * weights.adjacent;
+            features.sum += sum_row(&row) * weights.sum;
+        }
+        Some(features)
     }
 }
 
-impl Heuristic {
+impl RowHeuristic {
     /// Initializes the heuristic
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Evaluates a row and spits out a representation of how good it is. Bigger is better.
-    pub fn eval(&self, grid: Grid) -> f32 {
+    /// Initializes the heuristic with custom weights. This has to precompute a new table, so it
+    /// takes a few milliseconds.
+    pub fn with_weights(weights: HeuristicWeights) -> Self {
+        Self {
+            cache: build_cache(&weights),
+            weights,
+        }
+    }
+
+    /// The weights this heuristic was built from
+    pub fn weights(&self) -> HeuristicWeights {
+        self.weights
+    }
+
+    // Tiles bigger than 32768 are not cached, so only the rows and columns holding them are
+    // evaluated the slow way
+    #[cold]
+    #[inline(never)]
+    fn eval_by_row(&self, grid: Grid) -> f32 {
+        let transposed = grid.transpose();
         grid.rows()
             .iter()
-            .chain(grid.transpose().rows().iter())
-            .map(|&r| self.eval_row(r))
+            .zip(grid.row_extensions().iter())
+            .chain(
+                transposed
+                    .rows()
+                    .iter()
+                    .zip(transposed.row_extensions().iter()),
+            )
+            .map(|(&row, &ext)| match ext {
+                0 => self.eval_row(row),
+                _ => eval_row_nocache(&row.unpack_with_ext(ext), &self.weights),
+            })
             .sum()
     }
 
@@ -40,37 +247,32 @@ impl Heuristic {
 }
 
 // Pre-cache heuristic for every possible row with values that can fit a nibble
-lazy_static! {
-    static ref CACHE: Box<[f32]> = {
-        let mut vec = vec![0f32; u16::MAX as usize + 1];
-        for (index, row) in vec.iter_mut().enumerate() {
-            *row = eval_row_nocache(Row(index as u16));
-        }
-        vec.into()
-    };
+fn build_cache(weights: &HeuristicWeights) -> Arc<[f32]> {
+    let mut vec = vec![0f32; u16::MAX as usize + 1];
+    for (index, row) in vec.iter_mut().enumerate() {
+        *row = eval_row_nocache(&Row(index as u16).unpack(), weights);
+    }
+    vec.into()
 }
 
-const NOT_LOST: f32 = 200_000f32;
-const MONOTONICITY_STRENGTH: f32 = 47.0;
-const EMPTY_STRENGTH: f32 = 270.0;
-const ADJACENT_STRENGTH: f32 = 700.0;
-const SUM_STRENGTH: f32 = 11.0;
-
-fn eval_row_nocache(row: Row) -> f32 {
-    let empty = empty_tile_count_row(row) * EMPTY_STRENGTH;
-    let monotonicity = monotonicity_row(row) * MONOTONICITY_STRENGTH;
-    let adjacent = adjacent_row(row) * ADJACENT_STRENGTH;
-    let sum = sum_row(row) * SUM_STRENGTH;
-    NOT_LOST + monotonicity + empty + adjacent + sum
+lazy_static! {
+    static ref CACHE: Arc<[f32]> = build_cache(&HeuristicWeights::default());
 }
 
-fn empty_tile_count_row(row: Row) -> f32 {
-    bytecount::count(&row.unpack(), 0) as f32
+// Works for rows of any length, so boards other than 4x4 evaluate their rows with it too
+pub(crate) fn eval_row_nocache(row: &[u8], weights: &HeuristicWeights) -> f32 {
+    let empty = empty_tile_count_row(row) * weights.empty;
+    let monotonicity = monotonicity_row(row) * weights.monotonicity;
+    let adjacent = adjacent_row(row) * weights.adjacent;
+    let sum = sum_row(row) * weights.sum;
+    weights.not_lost + monotonicity + empty + adjacent + sum
 }
 
-fn monotonicity_row(row: Row) -> f32 {
-    let row = row.unpack();
+fn empty_tile_count_row(row: &[u8]) -> f32 {
+    bytecount::count(row, 0) as f32
+}
 
+fn monotonicity_row(row: &[u8]) -> f32 {
     let mut left = 0;
     let mut right = 0;
 
@@ -85,13 +287,11 @@ fn monotonicity_row(row: Row) -> f32 {
     -cmp::min(left, right) as f32
 }
 
-fn adjacent_row(row: Row) -> f32 {
-    let row = row.unpack();
-
+fn adjacent_row(row: &[u8]) -> f32 {
     let mut adjacent_count = 0;
     let mut y = 0;
 
-    while y < 3 {
+    while y + 1 < row.len() {
         if row[y] != 0 && row[y] == row[y + 1] {
             adjacent_count += 1;
             y += 2;
@@ -103,7 +303,111 @@ fn adjacent_row(row: Row) -> f32 {
     adjacent_count as f32
 }
 
-fn sum_row(row: Row) -> f32 {
-    let row = row.unpack();
+fn sum_row(row: &[u8]) -> f32 {
     -row.iter().map(|&v| f32::from(v).powf(3.5)).sum::<f32>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_build_heuristic_from_weights() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+
+        let default = RowHeuristic::new();
+        let same = RowHeuristic::with_weights(HeuristicWeights::default());
+        let different = RowHeuristic::with_weights(HeuristicWeights {
+            empty: 1000.0,
+            ..HeuristicWeights::default()
+        });
+
+        assert_eq!(default.eval(grid), same.eval(grid));
+        assert!(different.eval(grid) > default.eval(grid));
+    }
+
+    #[test]
+    fn features_add_up_to_evaluation() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let heuristic = RowHeuristic::new();
+
+        let features = heuristic.features(grid).unwrap();
+
+        assert_eq!(features.not_lost, 8.0 * heuristic.weights().not_lost);
+        assert_eq!(features.empty, 12.0 * heuristic.weights().empty);
+        assert!((features.total() - heuristic.eval(grid)).abs() < 1e-3 * heuristic.eval(grid));
+    }
+
+    #[test]
+    fn big_tiles_are_evaluated_like_small_ones() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 131_072],
+        ])
+        .unwrap();
+        let heuristic = RowHeuristic::new();
+        let weights = heuristic.weights();
+
+        let expected = grid
+            .unpack_log()
+            .iter()
+            .chain(grid.transpose().unpack_log().iter())
+            .map(|row| eval_row_nocache(row, &weights))
+            .sum::<f32>();
+
+        assert_eq!(heuristic.eval(grid), expected);
+    }
+
+    #[test]
+    fn can_parse_weights_from_display() {
+        let weights = HeuristicWeights {
+            not_lost: 1000.5,
+            monotonicity: -2.0,
+            empty: 3.25,
+            adjacent: 0.0,
+            sum: 1e-3,
+        };
+
+        let back = HeuristicWeights::from_display(&weights.to_string()).unwrap();
+
+        assert_eq!(weights, back);
+        assert!(HeuristicWeights::from_display("1, 2, 3, 4").is_none());
+        assert!(HeuristicWeights::from_display("1, 2, 3, 4, five").is_none());
+    }
+
+    #[test]
+    fn can_reject_weights_that_are_not_finite() {
+        assert!(HeuristicWeights::from_display("NaN, 2, 3, 4, 5").is_none());
+        assert!(HeuristicWeights::from_display("1, inf, 3, 4, 5").is_none());
+        assert!(HeuristicWeights::from_display("1, 2, 3, 4, -inf").is_none());
+    }
+
+    #[test]
+    fn can_save_and_load_weights() {
+        let weights = HeuristicWeights {
+            adjacent: 123.5,
+            ..HeuristicWeights::default()
+        };
+        let path = std::env::temp_dir().join("ai2048-can-save-and-load-weights.txt");
+
+        weights.save(&path).unwrap();
+        let back = HeuristicWeights::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(weights, back);
+    }
+}
@@ -1,6 +1,12 @@
 //! This crate provides an implementation of a 2048 AI.
 #![deny(missing_docs)]
 
+pub mod board;
 pub mod game_logic;
 pub mod heuristic;
+pub mod mcts;
+pub mod monte_carlo;
+pub mod ntuple;
+pub mod record;
+pub mod rollout;
 pub mod searcher;
@@ -0,0 +1,336 @@
+//! Monte Carlo tree search: an alternative to the expectimax searcher that needs no heuristic.
+//! It grows a tree of the most promising lines of play, and evaluates new positions by playing
+//! the rest of the game out.
+
+use crate::game_logic::{self, GameEngine, GameRng, Grid, Move, SpawnRule};
+use crate::rollout::{play_out, RolloutPolicy};
+use crate::searcher::{SearchResult, SearchStats};
+use instant::Instant;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// When to stop growing the tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    /// After this many rollouts
+    Iterations(u32),
+    /// After this much time, give or take one rollout
+    Time(Duration),
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::Iterations(DEFAULT_ITERATIONS)
+    }
+}
+
+/// Number of rollouts with the default budget
+pub const DEFAULT_ITERATIONS: u32 = 10_000;
+
+/// Default for `MctsSearcher::with_exploration`
+pub const DEFAULT_EXPLORATION: f32 = 0.5;
+
+// A position where the player is about to move
+#[derive(Debug)]
+struct Node {
+    visits: u32,
+    // Filled in on the first visit
+    moves: Vec<MoveNode>,
+}
+
+// A position right after a player move, before a tile spawns
+#[derive(Debug)]
+struct MoveNode {
+    mv: Move,
+    grid: Grid,
+    score: u32,
+    visits: u32,
+    // Sum of the points scored from here on over all visits
+    total: f64,
+    // The positions a spawned tile has led to so far, as indices of `Node`s
+    spawns: HashMap<Grid, usize>,
+}
+
+impl MoveNode {
+    fn mean(&self) -> f64 {
+        self.total / f64::from(self.visits)
+    }
+}
+
+/// Searches positions by Monte Carlo tree search. Move evaluations are the average number of
+/// points the searcher expects to score after making the move, and the best move is the one
+/// searched the most.
+#[derive(Clone, Debug)]
+pub struct MctsSearcher {
+    policy: RolloutPolicy,
+    budget: Budget,
+    exploration: f32,
+    game_engine: GameEngine,
+    rng: GameRng,
+}
+
+impl Default for MctsSearcher {
+    fn default() -> Self {
+        MctsSearcher {
+            policy: RolloutPolicy::default(),
+            budget: Budget::default(),
+            exploration: DEFAULT_EXPLORATION,
+            game_engine: GameEngine::new(),
+            rng: game_logic::seeded_rng(rand::random()),
+        }
+    }
+}
+
+impl MctsSearcher {
+    /// Initializes a searcher with random rollouts and the default budget
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plays rollouts according to `policy`
+    pub fn with_rollout_policy(self, policy: RolloutPolicy) -> Self {
+        MctsSearcher { policy, ..self }
+    }
+
+    /// Stops every search according to `budget`. At least one rollout is always played, so that
+    /// there is a move to pick.
+    pub fn with_budget(self, budget: Budget) -> Self {
+        let budget = match budget {
+            Budget::Iterations(limit) => Budget::Iterations(limit.max(1)),
+            budget => budget,
+        };
+        MctsSearcher { budget, ..self }
+    }
+
+    /// Weighs trying moves that have been searched less against sticking with the moves that
+    /// look best so far. The higher, the wider the tree.
+    pub fn with_exploration(self, exploration: f32) -> Self {
+        MctsSearcher {
+            exploration,
+            ..self
+        }
+    }
+
+    /// Expects new tiles to spawn according to `spawn_rule`
+    pub fn with_spawn_rule(self, spawn_rule: SpawnRule) -> Self {
+        MctsSearcher {
+            game_engine: GameEngine::with_spawn_rule(spawn_rule),
+            ..self
+        }
+    }
+
+    /// Makes the searcher repeat the same searches for the same seed
+    pub fn with_seed(self, seed: u64) -> Self {
+        MctsSearcher {
+            rng: game_logic::seeded_rng(seed),
+            ..self
+        }
+    }
+
+    /// Investigate a game state and determine move evaluations
+    pub fn search(&mut self, grid: Grid) -> SearchResult {
+        let start = Instant::now();
+        let mut tree = Tree {
+            nodes: vec![Node {
+                visits: 0,
+                moves: Vec::new(),
+            }],
+            stats: SearchStats::default(),
+            depth: 0,
+        };
+
+        let mut iterations = 0;
+        while !self.game_engine.game_over(grid) {
+            let done = match self.budget {
+                Budget::Iterations(limit) => iterations >= limit,
+                Budget::Time(budget) => iterations > 0 && start.elapsed() >= budget,
+            };
+            if done {
+                break;
+            }
+            self.iterate(grid, &mut tree);
+            iterations += 1;
+        }
+
+        let root = &tree.nodes[0];
+        let move_evaluations = root
+            .moves
+            .iter()
+            .filter(|m| m.visits > 0)
+            .map(|m| (m.mv, m.mean() as f32))
+            .collect();
+        let best_move = root.moves.iter().max_by_key(|m| m.visits).map(|m| m.mv);
+        tree.stats.cache_size = tree.nodes.len() as u32;
+
+        SearchResult {
+            root_grid: grid,
+            move_evaluations,
+            best_move,
+            stats: tree.stats,
+            depth: tree.depth,
+            min_probability: 0.0,
+        }
+    }
+
+    // Walks down the tree to a move not searched before, plays a rollout from there, and adds
+    // the points scored to every move on the way.
+    fn iterate(&mut self, root_grid: Grid, tree: &mut Tree) {
+        let mut path = Vec::new();
+        let mut node = 0;
+        let mut grid = root_grid;
+        let mut reward = loop {
+            tree.stats.nodes += 1;
+            if tree.nodes[node].moves.is_empty() {
+                tree.nodes[node].moves = self.expand(grid);
+                if tree.nodes[node].moves.is_empty() {
+                    break 0;
+                }
+            }
+            tree.nodes[node].visits += 1;
+
+            let index = self.select(&tree.nodes[node]);
+            path.push((node, index));
+            let next = tree.nodes.len();
+            let move_node = &mut tree.nodes[node].moves[index];
+            let first_visit = move_node.visits == 0;
+            grid = self
+                .game_engine
+                .add_random_tile(move_node.grid, &mut self.rng);
+            if first_visit {
+                tree.stats.evals += 1;
+                let rollout = play_out(self.game_engine, self.policy, grid, &mut self.rng);
+                tree.stats.nodes += rollout.moves;
+                break rollout.score;
+            }
+
+            node = match move_node.spawns.get(&grid) {
+                Some(&child) => child,
+                None => {
+                    move_node.spawns.insert(grid, next);
+                    tree.nodes.push(Node {
+                        visits: 0,
+                        moves: Vec::new(),
+                    });
+                    next
+                }
+            };
+        };
+
+        tree.depth = tree.depth.max(path.len().min(usize::from(u8::MAX)) as u8);
+        for &(node, index) in path.iter().rev() {
+            let move_node = &mut tree.nodes[node].moves[index];
+            reward += move_node.score;
+            move_node.visits += 1;
+            move_node.total += f64::from(reward);
+        }
+    }
+
+    fn expand(&self, grid: Grid) -> Vec<MoveNode> {
+        self.game_engine
+            .player_moves(grid)
+            .map(|(mv, new_grid)| MoveNode {
+                mv,
+                grid: new_grid,
+                score: self.game_engine.move_score(grid, mv),
+                visits: 0,
+                total: 0.0,
+                spawns: HashMap::new(),
+            })
+            .collect()
+    }
+
+    // Moves not searched yet come first. After that, UCB1 with rewards scaled by the best mean,
+    // as points have no upper bound.
+    fn select(&self, node: &Node) -> usize {
+        if let Some(index) = node.moves.iter().position(|m| m.visits == 0) {
+            return index;
+        }
+
+        let best_mean = node
+            .moves
+            .iter()
+            .map(MoveNode::mean)
+            .fold(0.0, f64::max)
+            .max(1.0);
+        let log_visits = f64::from(node.visits).ln();
+        let ucb = |m: &MoveNode| {
+            m.mean() / best_mean
+                + f64::from(self.exploration) * (log_visits / f64::from(m.visits)).sqrt()
+        };
+        (0..node.moves.len())
+            .max_by(|&a, &b| {
+                ucb(&node.moves[a])
+                    .partial_cmp(&ucb(&node.moves[b]))
+                    .expect("Failed to compare moves")
+            })
+            .expect("A node always has moves once expanded")
+    }
+}
+
+struct Tree {
+    nodes: Vec<Node>,
+    stats: SearchStats,
+    depth: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_search_with_mcts() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let mut searcher = MctsSearcher::new()
+            .with_seed(1)
+            .with_budget(Budget::Iterations(200));
+
+        let result = searcher.search(grid);
+
+        assert_eq!(result.root_grid, grid);
+        assert_eq!(result.stats.evals, 200);
+        assert!(result.best_move.is_some());
+        assert!(result.move_evaluations.values().all(|&eval| eval > 0.0));
+    }
+
+    #[test]
+    fn same_seed_gives_same_search() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let searcher = MctsSearcher::new()
+            .with_rollout_policy(RolloutPolicy::Greedy)
+            .with_budget(Budget::Iterations(100));
+
+        let first = searcher.clone().with_seed(7).search(grid);
+        let second = searcher.with_seed(7).search(grid);
+
+        assert_eq!(first.move_evaluations, second.move_evaluations);
+        assert_eq!(first.best_move, second.best_move);
+    }
+
+    #[test]
+    fn zero_iterations_still_pick_a_move() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let mut searcher = MctsSearcher::new()
+            .with_seed(1)
+            .with_budget(Budget::Iterations(0));
+
+        let result = searcher.search(grid);
+
+        assert_eq!(result.stats.evals, 1);
+        assert!(result.best_move.is_some());
+    }
+
+    #[test]
+    fn mcts_of_lost_game_has_no_move() {
+        let grid =
+            Grid::from_human([[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [4, 2, 4, 2]]).unwrap();
+        let mut searcher = MctsSearcher::new().with_budget(Budget::Time(Duration::from_millis(10)));
+
+        let result = searcher.search(grid);
+
+        assert_eq!(result.best_move, None);
+        assert!(result.move_evaluations.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Pure Monte Carlo: a baseline agent that plays every possible move, then plays the game out a
+//! number of times, and picks the move that did best on average.
+
+use crate::game_logic::{self, GameEngine, GameRng, Grid, SpawnRule};
+use crate::rollout::{play_out, RolloutPolicy};
+use crate::searcher::{SearchResult, SearchStats};
+
+/// Default for `RolloutAgent::with_games`
+pub const DEFAULT_GAMES: u32 = 100;
+
+/// What the games played out after a move are judged by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Objective {
+    /// Points scored by the move and the rest of the game
+    #[default]
+    Score,
+    /// Moves survived, counting the move itself
+    Survival,
+}
+
+/// Picks moves by playing the game out after every possible move. Move evaluations are the
+/// averages of the objective over the games played after the move.
+#[derive(Clone, Debug)]
+pub struct RolloutAgent {
+    games: u32,
+    policy: RolloutPolicy,
+    objective: Objective,
+    game_engine: GameEngine,
+    rng: GameRng,
+}
+
+impl Default for RolloutAgent {
+    fn default() -> Self {
+        RolloutAgent {
+            games: DEFAULT_GAMES,
+            policy: RolloutPolicy::default(),
+            objective: Objective::default(),
+            game_engine: GameEngine::new(),
+            rng: game_logic::seeded_rng(rand::random()),
+        }
+    }
+}
+
+impl RolloutAgent {
+    /// Initializes an agent that plays `DEFAULT_GAMES` random games per move for the best score
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plays `games` games after every possible move. At least one game is always played.
+    pub fn with_games(self, games: u32) -> Self {
+        RolloutAgent {
+            games: games.max(1),
+            ..self
+        }
+    }
+
+    /// Plays games according to `policy`
+    pub fn with_rollout_policy(self, policy: RolloutPolicy) -> Self {
+        RolloutAgent { policy, ..self }
+    }
+
+    /// Judges moves by `objective`
+    pub fn with_objective(self, objective: Objective) -> Self {
+        RolloutAgent { objective, ..self }
+    }
+
+    /// Expects new tiles to spawn according to `spawn_rule`
+    pub fn with_spawn_rule(self, spawn_rule: SpawnRule) -> Self {
+        RolloutAgent {
+            game_engine: GameEngine::with_spawn_rule(spawn_rule),
+            ..self
+        }
+    }
+
+    /// Makes the agent repeat the same searches for the same seed
+    pub fn with_seed(self, seed: u64) -> Self {
+        RolloutAgent {
+            rng: game_logic::seeded_rng(seed),
+            ..self
+        }
+    }
+
+    /// Investigate a game state and determine move evaluations
+    pub fn search(&mut self, grid: Grid) -> SearchResult {
+        let mut stats = SearchStats::default();
+        let mut move_evaluations = Vec::new();
+
+        for (mv, _) in self.game_engine.player_moves(grid) {
+            let mut total = 0f64;
+            for _ in 0..self.games {
+                let outcome = self.game_engine.make_scored_move(grid, mv);
+                let spawned = self
+                    .game_engine
+                    .add_random_tile(outcome.grid, &mut self.rng);
+                let rollout = play_out(self.game_engine, self.policy, spawned, &mut self.rng);
+                stats.nodes += rollout.moves + 1;
+                stats.evals += 1;
+                total += match self.objective {
+                    Objective::Score => f64::from(outcome.score + rollout.score),
+                    Objective::Survival => f64::from(rollout.moves + 1),
+                };
+            }
+            move_evaluations.push((mv, (total / f64::from(self.games)) as f32));
+        }
+
+        let best_move = move_evaluations
+            .iter()
+            .max_by(|a, b| {
+                a.1.partial_cmp(&b.1)
+                    .expect("Failed to compare evaluations")
+            })
+            .map(|&(mv, _)| mv);
+
+        SearchResult {
+            root_grid: grid,
+            move_evaluations: move_evaluations.into_iter().collect(),
+            best_move,
+            stats,
+            depth: 1,
+            min_probability: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_search_with_rollouts() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let mut agent = RolloutAgent::new().with_games(10).with_seed(1);
+
+        let result = agent.search(grid);
+
+        // Everything but Right
+        assert_eq!(result.move_evaluations.len(), 3);
+        assert_eq!(result.stats.evals, 30);
+        let best = result.best_move.unwrap();
+        assert!(result
+            .move_evaluations
+            .values()
+            .all(|&eval| eval <= result.move_evaluations[&best]));
+    }
+
+    #[test]
+    fn survival_counts_moves() {
+        // Only Up and Down are possible
+        let grid =
+            Grid::from_human([[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [2, 4, 2, 4]]).unwrap();
+        let mut agent = RolloutAgent::new()
+            .with_objective(Objective::Survival)
+            .with_games(5)
+            .with_seed(1);
+
+        let result = agent.search(grid);
+
+        assert_eq!(result.move_evaluations.len(), 2);
+        assert!(result.move_evaluations.values().all(|&eval| eval >= 1.0));
+    }
+}
@@ -0,0 +1,409 @@
+//! N-tuple network evaluator, trained by temporal difference learning
+
+use crate::game_logic::{GameEngine, Grid, MoveOutcome, MAX_EXPONENT, MOVES};
+use crate::heuristic::Heuristic;
+use rand::Rng;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The maximum number of cells in a tuple. A cell can hold any of 19 values, from empty to
+/// 262144, so a tuple of `n` cells has a table of `19^n` weights, and a tuple of 6 cells already
+/// takes 180 MiB.
+pub const MAX_TUPLE_LEN: usize = 6;
+
+/// The default shape of the network: a row, an inner row and three 2x2 squares. Together with
+/// their symmetric images they cover every row, column and square of the grid.
+pub const DEFAULT_TUPLES: [&[usize]; 5] = [
+    &[0, 1, 2, 3],
+    &[4, 5, 6, 7],
+    &[0, 1, 4, 5],
+    &[1, 2, 5, 6],
+    &[5, 6, 9, 10],
+];
+
+const CELLS: usize = 16;
+// Every tile a `Grid` can hold gets its own weights, so big tiles aren't mistaken for 32768
+const RADIX: usize = MAX_EXPONENT as usize + 1;
+const MAGIC: &[u8; 8] = b"AI2048NT";
+// Version 1 had 16 values per cell, with every tile from 32768 up sharing a value
+const VERSION: u32 = 2;
+
+#[derive(Debug, Clone)]
+struct Tuple {
+    cells: Vec<usize>,
+    // Cells of the 8 symmetric images of the tuple. All of them share the same weights.
+    images: Vec<Vec<usize>>,
+    weights: Vec<f32>,
+}
+
+impl Tuple {
+    fn new(cells: &[usize]) -> Option<Tuple> {
+        if cells.is_empty() || cells.len() > MAX_TUPLE_LEN {
+            return None;
+        }
+        for (index, &cell) in cells.iter().enumerate() {
+            if cell >= CELLS || cells[..index].contains(&cell) {
+                return None;
+            }
+        }
+
+        let images = (0..8)
+            .map(|symmetry| cells.iter().map(|&cell| reflect(cell, symmetry)).collect())
+            .collect();
+
+        Some(Tuple {
+            cells: cells.to_vec(),
+            images,
+            weights: vec![0.0; RADIX.pow(cells.len() as u32)],
+        })
+    }
+}
+
+// Maps a cell to its position under one of the 8 symmetries of the grid
+fn reflect(cell: usize, symmetry: u8) -> usize {
+    let (x, y) = (cell / 4, cell % 4);
+    let (x, y) = if symmetry & 0b100 != 0 {
+        (y, x)
+    } else {
+        (x, y)
+    };
+    let x = if symmetry & 0b010 != 0 { 3 - x } else { x };
+    let y = if symmetry & 0b001 != 0 { 3 - y } else { y };
+    x * 4 + y
+}
+
+fn index(grid: Grid, cells: &[usize]) -> usize {
+    cells.iter().fold(0, |index, &cell| {
+        index * RADIX + usize::from(grid.tile_log(cell))
+    })
+}
+
+/// A network of n-tuples that estimates how many points the rest of the game will bring,
+/// starting from an afterstate, that is, a grid right after a player move.
+///
+/// Each tuple is a handful of cells with a table holding a weight for every combination of
+/// tiles in them. The tuple is also laid over the 7 other symmetric images of the grid with the
+/// same weights, so the network treats all symmetric grids the same.
+#[derive(Debug, Clone)]
+pub struct NTupleNetwork {
+    tuples: Vec<Tuple>,
+}
+
+impl Default for NTupleNetwork {
+    fn default() -> Self {
+        NTupleNetwork::new(&DEFAULT_TUPLES).unwrap()
+    }
+}
+
+impl Heuristic for NTupleNetwork {
+    /// The searcher doesn't count the points gained along a line of play, so the leaf value adds
+    /// the points the tiles on the grid are worth to the points still expected.
+    fn eval(&self, grid: Grid) -> f32 {
+        self.value(grid) + tile_score(grid)
+    }
+
+    /// Every tuple is looked up in all 8 of its symmetric images.
+    fn is_symmetric(&self) -> bool {
+        true
+    }
+}
+
+impl NTupleNetwork {
+    /// Creates a network with all weights set to zero. Cells are numbered from the top left corner
+    /// row by row. Returns `None` if a tuple is empty, has more than `MAX_TUPLE_LEN` cells, has a
+    /// cell outside of the grid, or has the same cell twice.
+    pub fn new(tuples: &[&[usize]]) -> Option<NTupleNetwork> {
+        let tuples = tuples
+            .iter()
+            .map(|cells| Tuple::new(cells))
+            .collect::<Option<Vec<_>>>()?;
+        Some(NTupleNetwork { tuples })
+    }
+
+    /// The cells of every tuple
+    pub fn tuples(&self) -> impl Iterator<Item = &[usize]> {
+        self.tuples.iter().map(|tuple| tuple.cells.as_slice())
+    }
+
+    /// Estimates how many points the rest of the game will bring after a player move.
+    pub fn value(&self, grid: Grid) -> f32 {
+        self.tuples
+            .iter()
+            .map(|tuple| {
+                tuple
+                    .images
+                    .iter()
+                    .map(|cells| tuple.weights[index(grid, cells)])
+                    .sum::<f32>()
+            })
+            .sum()
+    }
+
+    /// Moves the value of `grid` towards `target`. The learning rate is shared between all the
+    /// weights that take part in the evaluation.
+    pub fn update(&mut self, grid: Grid, target: f32, learning_rate: f32) {
+        let lookups = (self.tuples.len() * 8) as f32;
+        let delta = (target - self.value(grid)) * learning_rate / lookups;
+        for tuple in &mut self.tuples {
+            for cells in &tuple.images {
+                tuple.weights[index(grid, cells)] += delta;
+            }
+        }
+    }
+
+    /// Reads a network written by `write_to`.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<NTupleNetwork> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not an n-tuple network"));
+        }
+        if read_u32(reader)? != VERSION {
+            return Err(invalid_data("unsupported n-tuple network version"));
+        }
+
+        let tuple_count = read_u32(reader)?;
+        let mut tuples = Vec::new();
+        for _ in 0..tuple_count {
+            let len = read_u32(reader)? as usize;
+            if len > MAX_TUPLE_LEN {
+                return Err(invalid_data("tuple is too long"));
+            }
+            let mut cells = vec![0u8; len];
+            reader.read_exact(&mut cells)?;
+            let cells = cells
+                .iter()
+                .map(|&cell| usize::from(cell))
+                .collect::<Vec<_>>();
+            let mut tuple = Tuple::new(&cells).ok_or_else(|| invalid_data("invalid tuple"))?;
+            for weight in tuple.weights.iter_mut() {
+                let mut bytes = [0u8; 4];
+                reader.read_exact(&mut bytes)?;
+                *weight = f32::from_le_bytes(bytes);
+            }
+            tuples.push(tuple);
+        }
+
+        Ok(NTupleNetwork { tuples })
+    }
+
+    /// Writes the network in a little endian binary format: the magic bytes `AI2048NT`, a `u32`
+    /// version, a `u32` number of tuples, and then for every tuple a `u32` number of cells, a byte
+    /// per cell, and all of its `f32` weights.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(self.tuples.len() as u32).to_le_bytes())?;
+        for tuple in &self.tuples {
+            writer.write_all(&(tuple.cells.len() as u32).to_le_bytes())?;
+            for &cell in &tuple.cells {
+                writer.write_all(&[cell as u8])?;
+            }
+            for weight in &tuple.weights {
+                writer.write_all(&weight.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a network from a file written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<NTupleNetwork> {
+        NTupleNetwork::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the network to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The points it takes to build the tiles on the grid out of 2s
+fn tile_score(grid: Grid) -> f32 {
+    (0..CELLS)
+        .map(|cell| match grid.tile_log(cell) {
+            0 => 0.0,
+            log => (f32::from(log) - 1.0) * (1u32 << log) as f32,
+        })
+        .sum()
+}
+
+/// The outcome of a single training game
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Episode {
+    /// The final score
+    pub score: u32,
+    /// The number of moves made
+    pub moves: u32,
+    /// The biggest tile reached
+    pub biggest_tile: u32,
+}
+
+/// Trains an `NTupleNetwork` by playing games against itself with TD(λ).
+///
+/// Moves are chosen greedily by the points they gain plus the value of the afterstate. Once a
+/// game is over, every afterstate of it is moved towards its λ-return, last to first. With a
+/// `lambda` of 0 this is plain TD(0).
+#[derive(Debug, Clone, Copy)]
+pub struct TdTrainer {
+    /// Step size of every update
+    pub learning_rate: f32,
+    /// How far the rewards of later moves reach back
+    pub lambda: f32,
+    /// The engine the games are played with
+    pub game_engine: GameEngine,
+}
+
+impl Default for TdTrainer {
+    fn default() -> Self {
+        TdTrainer {
+            learning_rate: 0.1,
+            lambda: 0.0,
+            game_engine: GameEngine::new(),
+        }
+    }
+}
+
+impl TdTrainer {
+    /// Plays a single game from an empty grid and learns from it.
+    pub fn play_episode<R: Rng + ?Sized>(
+        &self,
+        network: &mut NTupleNetwork,
+        rng: &mut R,
+    ) -> Episode {
+        let game_engine = self.game_engine;
+        let empty = Grid::default();
+        let mut grid = game_engine.add_random_tile(game_engine.add_random_tile(empty, rng), rng);
+        let mut history = Vec::new();
+        let mut episode = Episode::default();
+
+        while let Some(outcome) = self.choose_move(network, grid) {
+            history.push(outcome);
+            episode.score += outcome.score;
+            episode.moves += 1;
+            grid = game_engine.add_random_tile(outcome.grid, rng);
+        }
+        episode.biggest_tile = grid.biggest_tile();
+
+        // Nothing more is gained after the last afterstate
+        let mut target = 0.0;
+        for outcome in history.iter().rev() {
+            let value = network.value(outcome.grid);
+            network.update(outcome.grid, target, self.learning_rate);
+            target = outcome.score as f32 + (1.0 - self.lambda) * value + self.lambda * target;
+        }
+
+        episode
+    }
+
+    fn choose_move(&self, network: &NTupleNetwork, grid: Grid) -> Option<MoveOutcome> {
+        MOVES
+            .iter()
+            .map(|&mv| self.game_engine.make_scored_move(grid, mv))
+            .filter(|outcome| outcome.grid != grid)
+            .map(|outcome| (outcome.score as f32 + network.value(outcome.grid), outcome))
+            .fold(
+                None,
+                |best: Option<(f32, MoveOutcome)>, current| match best {
+                    Some(best) if best.0 >= current.0 => Some(best),
+                    _ => Some(current),
+                },
+            )
+            .map(|(_, outcome)| outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic;
+
+    #[test]
+    fn can_reject_invalid_tuples() {
+        assert!(NTupleNetwork::new(&[&[]]).is_none());
+        assert!(NTupleNetwork::new(&[&[0, 1, 2, 3, 4, 5, 6]]).is_none());
+        assert!(NTupleNetwork::new(&[&[0, 16]]).is_none());
+        assert!(NTupleNetwork::new(&[&[0, 1, 0]]).is_none());
+    }
+
+    #[test]
+    fn can_evaluate_symmetric_grids_the_same() {
+        let mut network = NTupleNetwork::default();
+        let trainer = TdTrainer::default();
+        let mut rng = game_logic::seeded_rng(0);
+        for _ in 0..10 {
+            trainer.play_episode(&mut network, &mut rng);
+        }
+
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+
+        let value = network.value(grid);
+        assert!(value != 0.0);
+        assert!((value - network.value(grid.transpose())).abs() < value.abs() * 1e-5);
+    }
+
+    #[test]
+    fn can_move_value_towards_target() {
+        let mut network = NTupleNetwork::default();
+        // No two tiles are the same, so every lookup hits a different weight
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [32, 64, 128, 256],
+            [512, 1024, 2048, 4096],
+            [8192, 16384, 32768, 0],
+        ])
+        .unwrap();
+
+        network.update(grid, 100.0, 0.5);
+
+        assert!((network.value(grid) - 50.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn can_write_and_read_network() {
+        let mut network = NTupleNetwork::new(&[&[0, 1, 2], &[5, 9]]).unwrap();
+        let grid =
+            Grid::from_human([[2, 2, 4, 4], [0, 2, 2, 0], [0, 2, 2, 2], [2, 0, 0, 2]]).unwrap();
+        network.update(grid, 1000.0, 1.0);
+
+        let mut bytes = Vec::new();
+        network.write_to(&mut bytes).unwrap();
+        let back = NTupleNetwork::read_from(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(
+            network.tuples().collect::<Vec<_>>(),
+            back.tuples().collect::<Vec<_>>()
+        );
+        assert_eq!(network.value(grid), back.value(grid));
+        assert!(NTupleNetwork::read_from(&mut &bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn can_tell_big_tiles_apart() {
+        let mut network = NTupleNetwork::new(&[&[0]]).unwrap();
+        let grid = |tile| Grid::from_human([[tile, 0, 0, 0], [0; 4], [0; 4], [0; 4]]).unwrap();
+
+        network.update(grid(65536), 1000.0, 1.0);
+
+        assert!(network.value(grid(65536)) > network.value(grid(32768)));
+        assert!(network.value(grid(65536)) > network.value(grid(131072)));
+    }
+}
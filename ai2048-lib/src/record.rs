@@ -0,0 +1,375 @@
+//! Game records: the starting grid, every move, and every spawned tile of a game, so that the
+//! game can be archived and replayed later.
+
+use crate::game_logic::{GameEngine, Grid, Move, MOVES};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const HEADER: &str = "ai2048 game record";
+const VERSION: u32 = 1;
+
+/// A tile that appeared on the grid after a move.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct Spawn {
+    /// The cell, counting from the top left corner row by row
+    pub index: usize,
+    /// The value of the tile, such as 2 or 4
+    pub value: u32,
+}
+
+impl Spawn {
+    /// Finds the tile that was spawned on `moved` to get `spawned`. Returns `None` unless the
+    /// grids differ by exactly one tile that's empty in `moved`.
+    pub fn between(moved: Grid, spawned: Grid) -> Option<Spawn> {
+        let before = moved.unpack_human();
+        let after = spawned.unpack_human();
+        let mut spawn = None;
+        for (index, (&old, &new)) in before
+            .iter()
+            .flatten()
+            .zip(after.iter().flatten())
+            .enumerate()
+        {
+            if old == new {
+                continue;
+            }
+            if old != 0 || spawn.is_some() {
+                return None;
+            }
+            spawn = Some(Spawn { index, value: new });
+        }
+        spawn
+    }
+
+    /// Puts the tile on `grid`. Returns `None` if the cell is taken or the tile is not a valid
+    /// tile.
+    pub fn apply(self, grid: Grid) -> Option<Grid> {
+        let mut tiles = grid.unpack_human();
+        let cell = tiles.iter_mut().flatten().nth(self.index)?;
+        if *cell != 0 || self.value == 0 {
+            return None;
+        }
+        *cell = self.value;
+        Grid::from_human(tiles).ok()
+    }
+}
+
+/// A move and the tile that spawned after it.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct Turn {
+    /// The move of the player
+    pub mv: Move,
+    /// The tile that spawned after the move
+    pub spawn: Spawn,
+}
+
+/// Everything needed to replay a game.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct GameRecord {
+    /// The grid the game started from, with its first tiles already spawned
+    pub start: Grid,
+    /// Every turn of the game, in order
+    pub turns: Vec<Turn>,
+}
+
+/// The outcome of a replayed game.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Replay {
+    /// The grid after every turn, starting with the grid the game started from
+    pub grids: Vec<Grid>,
+    /// Points gained by all the moves
+    pub score: u32,
+}
+
+impl Replay {
+    /// The grid the game ended on
+    pub fn last_grid(&self) -> Grid {
+        *self.grids.last().expect("A replay has at least one grid")
+    }
+}
+
+/// Why a record doesn't replay.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum ReplayError {
+    /// The move of the turn doesn't change the grid
+    IllegalMove {
+        /// The turn, counting from 0
+        turn: usize,
+    },
+    /// The tile of the turn spawned on a taken cell, or is not a valid tile
+    InvalidSpawn {
+        /// The turn, counting from 0
+        turn: usize,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::IllegalMove { turn } => write!(f, "turn {}: the move is illegal", turn),
+            ReplayError::InvalidSpawn { turn } => {
+                write!(f, "turn {}: the tile can't spawn there", turn)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Why a turn can't be added to a record: the grids before and after the spawn don't differ by
+/// exactly one new tile.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct SpawnError;
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "exactly one tile must spawn after a move")
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+impl GameRecord {
+    /// Starts a record of a game that starts from `start`
+    pub fn new(start: Grid) -> GameRecord {
+        GameRecord {
+            start,
+            turns: Vec::new(),
+        }
+    }
+
+    /// Adds a turn where `mv` turned the grid into `moved`, and a tile spawned on it to make
+    /// `spawned`. Returns an error, and records nothing, unless exactly one tile spawned.
+    pub fn push(&mut self, mv: Move, moved: Grid, spawned: Grid) -> Result<(), SpawnError> {
+        let spawn = Spawn::between(moved, spawned).ok_or(SpawnError)?;
+        self.turns.push(Turn { mv, spawn });
+        Ok(())
+    }
+
+    /// Plays the game again with `game_engine`, checking that every move is legal and every tile
+    /// spawns on an empty cell.
+    pub fn replay(&self, game_engine: GameEngine) -> Result<Replay, ReplayError> {
+        let mut grids = Vec::with_capacity(self.turns.len() + 1);
+        grids.push(self.start);
+        let mut grid = self.start;
+        let mut score = 0;
+        for (turn, &Turn { mv, spawn }) in self.turns.iter().enumerate() {
+            let outcome = game_engine.make_scored_move(grid, mv);
+            if outcome.grid == grid {
+                return Err(ReplayError::IllegalMove { turn });
+            }
+            grid = spawn
+                .apply(outcome.grid)
+                .ok_or(ReplayError::InvalidSpawn { turn })?;
+            score += outcome.score;
+            grids.push(grid);
+        }
+        Ok(Replay { grids, score })
+    }
+
+    /// Reads a record written by `write_to`.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<GameRecord> {
+        let mut lines = reader.lines();
+        let mut next_line = || -> io::Result<Option<String>> {
+            for line in &mut lines {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    return Ok(Some(line));
+                }
+            }
+            Ok(None)
+        };
+
+        let header = next_line()?.ok_or_else(|| invalid_data("empty game record"))?;
+        if header.trim() != format!("{} {}", HEADER, VERSION) {
+            return Err(invalid_data("not a game record of a supported version"));
+        }
+
+        let start = next_line()?.ok_or_else(|| invalid_data("no starting grid"))?;
+        let start = match start.trim().splitn(2, ' ').collect::<Vec<_>>()[..] {
+            ["start", grid] => Grid::from_display(grid)
+                .map_err(|e| invalid_data(&format!("invalid starting grid: {}", e)))?,
+            _ => return Err(invalid_data("no starting grid")),
+        };
+
+        let mut record = GameRecord::new(start);
+        while let Some(line) = next_line()? {
+            let turn = parse_turn(&line)
+                .ok_or_else(|| invalid_data(&format!("invalid turn: {}", line.trim())))?;
+            record.turns.push(turn);
+        }
+        Ok(record)
+    }
+
+    /// Writes the record in a text format: a header line with the version, the starting grid as
+    /// 16 numbers row by row, and then a line per turn with the move, the cell of the spawned
+    /// tile counting row by row from the top left corner, and the value of the tile. For example:
+    ///
+    /// ```text
+    /// ai2048 game record 1
+    /// start 0 0 2 0 0 0 0 0 0 0 0 0 0 2 0 0
+    /// Left 3 2
+    /// Down 0 4
+    /// ```
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "{} {}", HEADER, VERSION)?;
+        let tiles = self.start.unpack_human();
+        write!(writer, "start")?;
+        for tile in tiles.iter().flatten() {
+            write!(writer, " {}", tile)?;
+        }
+        writeln!(writer)?;
+        for turn in &self.turns {
+            writeln!(
+                writer,
+                "{} {} {}",
+                turn.mv, turn.spawn.index, turn.spawn.value
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reads a record from a file written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<GameRecord> {
+        GameRecord::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes the record to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+}
+
+fn parse_turn(line: &str) -> Option<Turn> {
+    let parts = line.split_whitespace().collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return None;
+    }
+    let mv = *MOVES.iter().find(|mv| mv.to_string() == parts[0])?;
+    let index = parts[1].parse().ok().filter(|&index| index < 16)?;
+    let value = parts[2].parse().ok()?;
+    Some(Turn {
+        mv,
+        spawn: Spawn { index, value },
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic;
+
+    fn play_recorded_game(moves: usize) -> (GameRecord, Grid) {
+        let game_engine = GameEngine::new();
+        let mut rng = game_logic::seeded_rng(3);
+        let start = Grid::default()
+            .add_random_tile_with(&mut rng)
+            .add_random_tile_with(&mut rng);
+        let mut record = GameRecord::new(start);
+        let mut grid = start;
+        for _ in 0..moves {
+            let (mv, moved) = match game_engine.player_moves(grid).next() {
+                Some(next) => next,
+                None => break,
+            };
+            let spawned = moved.add_random_tile_with(&mut rng);
+            record.push(mv, moved, spawned).unwrap();
+            grid = spawned;
+        }
+        (record, grid)
+    }
+
+    #[test]
+    fn can_replay_recorded_game() {
+        let (record, grid) = play_recorded_game(50);
+
+        let replay = record.replay(GameEngine::new()).unwrap();
+
+        assert_eq!(replay.grids.len(), record.turns.len() + 1);
+        assert_eq!(replay.last_grid(), grid);
+        assert!(replay.score > 0);
+    }
+
+    #[test]
+    fn can_read_written_record() {
+        let (record, _) = play_recorded_game(20);
+
+        let mut bytes = Vec::new();
+        record.write_to(&mut bytes).unwrap();
+        let read = GameRecord::read_from(&mut &bytes[..]).unwrap();
+
+        assert_eq!(read, record);
+    }
+
+    #[test]
+    fn replay_rejects_tampered_record() {
+        let (mut record, _) = play_recorded_game(20);
+        record.turns[5].spawn.value = 3;
+        let start =
+            Grid::from_human([[2, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]).unwrap();
+        let mut illegal = GameRecord::new(start);
+        illegal.turns.push(Turn {
+            mv: Move::Left,
+            spawn: Spawn { index: 1, value: 2 },
+        });
+
+        assert_eq!(
+            record.replay(GameEngine::new()),
+            Err(ReplayError::InvalidSpawn { turn: 5 })
+        );
+        assert_eq!(
+            illegal.replay(GameEngine::new()),
+            Err(ReplayError::IllegalMove { turn: 0 })
+        );
+    }
+
+    #[test]
+    fn push_rejects_turn_without_one_spawn() {
+        let moved =
+            Grid::from_human([[4, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]).unwrap();
+        let spawned_two =
+            Grid::from_human([[4, 0, 0, 2], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let mut record = GameRecord::new(moved);
+
+        assert_eq!(record.push(Move::Left, moved, moved), Err(SpawnError));
+        assert_eq!(record.push(Move::Left, moved, spawned_two), Err(SpawnError));
+        assert!(record.turns.is_empty());
+    }
+
+    #[test]
+    fn read_rejects_garbage() {
+        let text = "ai2048 game record 1\nstart 0 0 2 0 0 0 0 0 0 0 0 0 0 2 0 0\nSideways 3 2\n";
+
+        assert!(GameRecord::read_from(&mut text.as_bytes()).is_err());
+        assert!(GameRecord::read_from(&mut "not a record".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn finds_spawned_tile() {
+        let moved =
+            Grid::from_human([[2, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]).unwrap();
+        let spawned =
+            Grid::from_human([[2, 0, 0, 0], [0, 0, 0, 0], [0, 0, 4, 0], [0, 0, 0, 0]]).unwrap();
+
+        let spawn = Spawn::between(moved, spawned).unwrap();
+
+        assert_eq!(
+            spawn,
+            Spawn {
+                index: 10,
+                value: 4
+            }
+        );
+        assert_eq!(spawn.apply(moved), Some(spawned));
+        assert_eq!(Spawn::between(moved, moved), None);
+        assert_eq!(Spawn::between(spawned, moved), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Playing a game out to the end with a simple policy, which the Monte Carlo agents use to
+//! evaluate positions.
+
+use crate::game_logic::{GameEngine, Grid};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+/// How a rollout picks moves when it plays a game out from a new position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RolloutPolicy {
+    /// Picks any possible move with equal chance
+    #[default]
+    Random,
+    /// Picks the move that scores the most points, and a random one if none scores any
+    Greedy,
+}
+
+/// The outcome of a game played out to the end
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Rollout {
+    /// Points scored
+    pub score: u32,
+    /// Moves made
+    pub moves: u32,
+}
+
+// Plays the game out from `grid`, picking moves according to `policy`
+pub(crate) fn play_out<R: Rng + ?Sized>(
+    game_engine: GameEngine,
+    policy: RolloutPolicy,
+    mut grid: Grid,
+    rng: &mut R,
+) -> Rollout {
+    let mut rollout = Rollout::default();
+    let mut moves = Vec::with_capacity(4);
+    loop {
+        moves.clear();
+        moves.extend(game_engine.player_moves(grid).map(|(mv, _)| mv));
+        let mv = match policy {
+            RolloutPolicy::Random => moves.choose(rng).cloned(),
+            RolloutPolicy::Greedy => {
+                let greedy = moves
+                    .iter()
+                    .map(|&mv| (game_engine.move_score(grid, mv), mv))
+                    .filter(|&(points, _)| points > 0)
+                    .max_by_key(|&(points, _)| points)
+                    .map(|(_, mv)| mv);
+                greedy.or_else(|| moves.choose(rng).cloned())
+            }
+        };
+        let mv = match mv {
+            Some(mv) => mv,
+            None => return rollout,
+        };
+
+        let outcome = game_engine.make_scored_move(grid, mv);
+        rollout.score += outcome.score;
+        rollout.moves += 1;
+        grid = game_engine.add_random_tile(outcome.grid, rng);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic;
+
+    #[test]
+    fn plays_game_out_to_the_end() {
+        let game_engine = GameEngine::new();
+        let lost =
+            Grid::from_human([[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [4, 2, 4, 2]]).unwrap();
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+
+        for &policy in &[RolloutPolicy::Random, RolloutPolicy::Greedy] {
+            let mut rng = game_logic::seeded_rng(3);
+            let rollout = play_out(game_engine, policy, grid, &mut rng);
+            let mut rng = game_logic::seeded_rng(3);
+
+            assert_eq!(
+                play_out(game_engine, policy, lost, &mut rng),
+                Rollout::default()
+            );
+            assert!(rollout.moves > 0);
+            assert!(rollout.score > 0);
+        }
+    }
+}
@@ -1,11 +1,16 @@
 //! Searcher looks for the best move given a game position
 
-use crate::game_logic::{GameEngine, Grid, Move};
-use crate::heuristic::Heuristic;
+use crate::game_logic::{GameEngine, Grid, Move, SpawnRule};
+use crate::heuristic::{Features, Heuristic, RowHeuristic};
+use crate::record::Spawn;
 use cfg_if::cfg_if;
+use instant::Instant;
 use std::collections::HashMap;
 use std::f32;
 use std::ops::Add;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 cfg_if! {
     if #[cfg(feature = "fnv")] {
@@ -21,19 +26,20 @@ cfg_if! {
 
 cfg_if! {
     if #[cfg(feature = "hashbrown")] {
-        type Cache<K, V> = hashbrown::HashMap<K, V, BuildHasher>;
+        pub(crate) type Cache<K, V> = hashbrown::HashMap<K, V, BuildHasher>;
     } else if #[cfg(feature = "indexmap")] {
-        type Cache<K, V> = indexmap::map::IndexMap<K, V, BuildHasher>;
+        pub(crate) type Cache<K, V> = indexmap::map::IndexMap<K, V, BuildHasher>;
     } else {
-        type Cache<K, V> = std::collections::HashMap<K, V, BuildHasher>;
+        pub(crate) type Cache<K, V> = std::collections::HashMap<K, V, BuildHasher>;
     }
 }
 
 /// Return a number of interesting statistics together with a recommendation for the best move.
+/// Searches of boards other than 4x4 give results for a `Board` instead of a `Grid`.
 #[derive(Clone, Debug, Default)]
-pub struct SearchResult {
+pub struct SearchResult<G = Grid> {
     /// The game state for which analysis was conducted.
-    pub root_grid: Grid,
+    pub root_grid: G,
     /// A map of evaluations. Can be empty if the player has no more moves, that is,
     /// in a game over state.
     pub move_evaluations: HashMap<Move, f32>,
@@ -44,6 +50,8 @@ pub struct SearchResult {
     pub stats: SearchStats,
     /// Search depth
     pub depth: u8,
+    /// Cutoff probability the search was run with
+    pub min_probability: f32,
 }
 
 /// Some search statistics
@@ -55,12 +63,49 @@ pub struct SearchStats {
     pub cache_size: u32,
     /// Evaluated from cache
     pub cache_hits: u32,
+    /// Evaluated from cache entries kept from earlier searches. These are also counted in
+    /// `cache_hits`.
+    pub reused_hits: u32,
+    /// Evaluated from cache entries stored for a rotation or reflection of the position. Only
+    /// happens with canonical keys. These are also counted in `cache_hits`.
+    pub symmetric_hits: u32,
     /// Evaluated with heuristic
     pub evals: u32,
     /// Evaluated as average of children
     pub average: u32,
 }
 
+/// A search result together with the line of play behind it, as given by `Searcher::explain`.
+#[derive(Clone, Debug, Default)]
+pub struct Explanation {
+    /// The search being explained
+    pub result: SearchResult,
+    /// The line of play the search expects: the best move of every turn, each followed by the
+    /// spawn that hurts most among the likeliest ones, down to the search horizon. Empty in a
+    /// game over state.
+    pub principal_variation: Vec<Ply>,
+    /// The grid at the end of the principal variation
+    pub leaf: Grid,
+    /// What the features of the heuristic contribute to its evaluation of `leaf`, if the
+    /// heuristic is made of features
+    pub features: Option<Features>,
+    /// The chance of game over within the search horizon, if every move is the one the search
+    /// rates best
+    pub game_over_probability: f32,
+}
+
+/// A turn of the principal variation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ply {
+    /// The move the search rates best
+    pub mv: Move,
+    /// The evaluation of the move
+    pub eval: f32,
+    /// The tile expected to spawn after the move, and the chance it spawns there. `None` at the
+    /// search horizon.
+    pub spawn: Option<(Spawn, f32)>,
+}
+
 impl Add for SearchStats {
     type Output = Self;
 
@@ -69,172 +114,1735 @@ impl Add for SearchStats {
             nodes: self.nodes + other.nodes,
             cache_size: self.cache_size + other.cache_size,
             cache_hits: self.cache_hits + other.cache_hits,
+            reused_hits: self.reused_hits + other.reused_hits,
+            symmetric_hits: self.symmetric_hits + other.symmetric_hits,
             evals: self.evals + other.evals,
             average: self.average + other.average,
         }
     }
 }
 
+/// Lets another thread stop a search that is in progress. Clones share the same flag.
 #[derive(Clone, Debug, Default)]
-struct SearchState {
-    cache: Cache<Grid, (f32, f32)>,
-    stats: SearchStats,
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes every search watching this token stop as soon as possible
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A snapshot of a search that is still in progress
+#[derive(Clone, Debug, Default)]
+pub struct SearchProgress {
+    /// Evaluations of the moves that have been searched completely so far
+    pub move_evaluations: HashMap<Move, f32>,
+    /// Nodes travelled so far
+    pub nodes: u32,
+    /// Depth of the search in progress
+    pub depth: u8,
+}
+
+/// Ways to watch and stop a search from the outside.
+#[derive(Clone, Default)]
+pub struct SearchControl<'a> {
+    token: Option<CancellationToken>,
+    cancel_check: Option<&'a (dyn Fn() -> bool + Sync)>,
+    progress: Option<&'a (dyn Fn(&SearchProgress) + Sync)>,
+}
+
+impl<'a> SearchControl<'a> {
+    /// A search that can't be stopped and doesn't report progress
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the search once `token` is cancelled
+    pub fn with_token(self, token: CancellationToken) -> Self {
+        SearchControl {
+            token: Some(token),
+            ..self
+        }
+    }
+
+    /// Stops the search once `cancel_check` returns `true`. It's polled every few thousand
+    /// nodes, so it has to be cheap, such as a load from memory some other thread writes to.
+    pub fn with_cancel_check(self, cancel_check: &'a (dyn Fn() -> bool + Sync)) -> Self {
+        SearchControl {
+            cancel_check: Some(cancel_check),
+            ..self
+        }
+    }
+
+    /// Calls `progress` every time a move has been searched completely, and every few
+    /// hundred thousand nodes in between. With the `parallel` feature it's called from several
+    /// threads.
+    pub fn with_progress(self, progress: &'a (dyn Fn(&SearchProgress) + Sync)) -> Self {
+        SearchControl {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+            || self.cancel_check.is_some_and(|cancelled| cancelled())
+    }
+}
+
+// Shared between all threads working on the same search
+struct Monitor<'a> {
+    control: &'a SearchControl<'a>,
+    deadline: Option<Instant>,
+    depth: u8,
+    nodes: AtomicU32,
+    move_evaluations: Mutex<HashMap<Move, f32>>,
+}
+
+impl<'a> Monitor<'a> {
+    fn new(control: &'a SearchControl<'a>, deadline: Option<Instant>, depth: u8) -> Self {
+        Monitor {
+            control,
+            deadline,
+            depth,
+            nodes: AtomicU32::new(0),
+            move_evaluations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn should_stop(&self) -> bool {
+        self.control.is_cancelled() || self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    fn add_nodes(&self, nodes: u32) {
+        self.nodes.fetch_add(nodes, Ordering::Relaxed);
+    }
+
+    fn finish_move(&self, mv: Move, eval: f32) {
+        if self.control.progress.is_some() {
+            self.move_evaluations
+                .lock()
+                .expect("Failed to record evaluation")
+                .insert(mv, eval);
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        if let Some(progress) = self.control.progress {
+            let move_evaluations = self
+                .move_evaluations
+                .lock()
+                .expect("Failed to read evaluations")
+                .clone();
+            progress(&SearchProgress {
+                move_evaluations,
+                nodes: self.nodes.load(Ordering::Relaxed),
+                depth: self.depth,
+            });
+        }
+    }
+}
+
+// A cached evaluation. It's good enough for a lookup if it was searched at least as deep and at
+// least as far from the cutoff probability as the lookup requires. Both can differ for the same
+// grid even within one search: a 4 spawning adds as much as two 2s, so a grid can be reached
+// after a different number of turns, and so at a different depth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    // Probability of reaching the position divided by the cutoff probability
+    headroom: f32,
+    depth: u8,
+    // Number of the search that last used the entry, for aging. Wraps around.
+    generation: u8,
+    // Which rotation or reflection of the position was evaluated, with canonical keys
+    symmetry: u8,
+    eval: f32,
+}
+
+impl Entry {
+    fn covers(&self, headroom: f32, depth: u8) -> bool {
+        self.headroom >= headroom && self.depth >= depth
+    }
+}
+
+// Entries not used by this many consecutive searches are the first to go
+const MAX_AGE: u8 = 16;
+
+cfg_if! {
+    if #[cfg(feature = "parallel")] {
+        type Table = SharedTable;
+        type TableRef<'a> = &'a SharedTable;
+    } else {
+        type Table = LocalTable;
+        type TableRef<'a> = &'a mut LocalTable;
+    }
+}
+
+// The table of a single-threaded search. Grows as needed.
+#[cfg_attr(feature = "parallel", allow(dead_code))]
+#[derive(Clone, Debug, Default)]
+struct LocalTable(Cache<Grid, Entry>);
+
+#[cfg_attr(feature = "parallel", allow(dead_code))]
+impl LocalTable {
+    fn for_search(_min_probability: f32) -> Self {
+        Self::default()
+    }
+
+    fn with_memory_limit(_bytes: usize) -> Self {
+        Self::default()
+    }
+
+    fn get(&self, key: Grid) -> Option<Entry> {
+        self.0.get(&key).cloned()
+    }
+
+    fn insert(&mut self, key: Grid, entry: Entry) {
+        self.0.insert(key, entry);
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    // Brings the table down to about 3/4 of `capacity`, so that this doesn't have to happen
+    // after every search.
+    fn trim(&mut self, root_grid: Grid, generation: u8, capacity: usize) {
+        if self.0.len() <= capacity {
+            return;
+        }
+        let target = capacity / 4 * 3;
+
+        // Tiles never disappear, so positions with a smaller sum can't come up again
+        let sum = root_grid.tile_sum();
+        let mut max_age = MAX_AGE;
+        self.0.retain(|grid, entry| {
+            generation.wrapping_sub(entry.generation) <= max_age && grid.tile_sum() >= sum
+        });
+        while self.0.len() > target && max_age > 0 {
+            max_age /= 2;
+            self.0
+                .retain(|_, entry| generation.wrapping_sub(entry.generation) <= max_age);
+        }
+        // Everything left is from the latest search, so there's nothing better to go by
+        let mut left = target;
+        if self.0.len() > left {
+            self.0.retain(|_, _| {
+                if left == 0 {
+                    return false;
+                }
+                left -= 1;
+                true
+            });
+        }
+    }
+}
+
+// A fixed size table that threads read and write without locks. A slot holds the packed entry
+// and the key XOR the packed entry. If another thread tears a slot by writing at the same
+// time, the two no longer match, and the slot reads as empty. Grids with tiles beyond 32768
+// don't fit the board alone, so their extensions are kept apart, next to the slot index, and
+// their entries are flagged. Other grids never have to read them, so they cost nothing until
+// the game gets that far.
+#[cfg(feature = "parallel")]
+struct SharedTable {
+    slots: Box<[Slot]>,
+    exts: Box<[AtomicU32]>,
+    len: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(feature = "parallel")]
+#[derive(Default)]
+struct Slot {
+    check: std::sync::atomic::AtomicU64,
+    data: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(feature = "parallel")]
+impl std::fmt::Debug for SharedTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SharedTable")
+            .field("slots", &self.slots.len())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl Clone for SharedTable {
+    fn clone(&self) -> Self {
+        let table = SharedTable::with_slots(self.slots.len());
+        for (slot, copy) in self.slots.iter().zip(table.slots.iter()) {
+            copy.check
+                .store(slot.check.load(Ordering::Relaxed), Ordering::Relaxed);
+            copy.data
+                .store(slot.data.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        for (ext, copy) in self.exts.iter().zip(table.exts.iter()) {
+            copy.store(ext.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        table.len.store(self.len(), Ordering::Relaxed);
+        table
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl SharedTable {
+    fn with_slots(slots: usize) -> Self {
+        let slots = slots.next_power_of_two();
+        // Safety: all zeroes is an empty `Slot`, and an extension of 0
+        unsafe {
+            SharedTable {
+                slots: alloc_zeroed_slice(slots),
+                exts: alloc_zeroed_slice(slots),
+                len: Default::default(),
+            }
+        }
+    }
+
+    // Roughly as many slots as a search with this cutoff fills
+    fn for_search(min_probability: f32) -> Self {
+        let slots = (64.0 / min_probability).min((1 << 20) as f32).max(1024.0);
+        Self::with_slots(slots as usize)
+    }
+
+    fn with_memory_limit(bytes: usize) -> Self {
+        let slots = (bytes / ENTRY_SIZE).max(1);
+        if slots.is_power_of_two() {
+            Self::with_slots(slots)
+        } else {
+            // Round down, so that the limit holds
+            Self::with_slots(slots.next_power_of_two() / 2)
+        }
+    }
+
+    // The slot of `key`, and the board mixed with the extension, which the slot checks against
+    fn slot(&self, key: Grid) -> (usize, u64) {
+        let key = key.board() ^ u64::from(key.ext()).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        let hash = key.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let index = (hash >> 32) as usize & (self.slots.len() - 1);
+        (index, key)
+    }
+
+    fn get(&self, key: Grid) -> Option<Entry> {
+        let (index, mixed) = self.slot(key);
+        let slot = &self.slots[index];
+        let data = slot.data.load(Ordering::Relaxed);
+        let check = slot.check.load(Ordering::Relaxed);
+        if data == 0 || check ^ data != mixed {
+            return None;
+        }
+        if key.fits_nibbles() {
+            if data & EXT_FLAG != 0 {
+                return None;
+            }
+        } else if data & EXT_FLAG == 0 || self.exts[index].load(Ordering::Relaxed) != key.ext() {
+            return None;
+        }
+        Some(unpack_entry(data))
+    }
+
+    fn insert(&self, key: Grid, entry: Entry) {
+        let (index, mixed) = self.slot(key);
+        let slot = &self.slots[index];
+        let old = slot.data.load(Ordering::Relaxed);
+        if old == 0 {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        } else if slot.check.load(Ordering::Relaxed) ^ old != mixed {
+            // Deeper entries of other positions from the same search save more work
+            let old = unpack_entry(old);
+            if old.generation == entry.generation && old.depth > entry.depth {
+                return;
+            }
+        }
+        let mut data = pack_entry(entry);
+        if !key.fits_nibbles() {
+            self.exts[index].store(key.ext(), Ordering::Relaxed);
+            data |= EXT_FLAG;
+        }
+        slot.data.store(data, Ordering::Relaxed);
+        slot.check.store(mixed ^ data, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    // Old entries get overwritten as the table fills up, so there's nothing to do
+    fn trim(&mut self, _root_grid: Grid, _generation: u8, _capacity: usize) {}
+}
+
+// Zeroed memory is handed out by the OS lazily, so a big table only costs as much as is actually
+// used. Safety: all zeroes has to be a valid `T`.
+#[cfg(feature = "parallel")]
+unsafe fn alloc_zeroed_slice<T>(len: usize) -> Box<[T]> {
+    use std::alloc::{self, Layout};
+
+    let layout = Layout::array::<T>(len).expect("Transposition table is too big");
+    let ptr = alloc::alloc_zeroed(layout) as *mut T;
+    if ptr.is_null() {
+        alloc::handle_alloc_error(layout);
+    }
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len))
+}
+
+// Keeps the upper half of the bits of a headroom, rounding it down, so that entries pack into 64
+// bits. Lookups have to be rounded the same way, or they would never find the entries of the
+// very same position.
+fn quantize_headroom(headroom: f32) -> f32 {
+    f32::from_bits(headroom.to_bits() & 0xFFFF_0000)
+}
+
+// Marks a packed entry of a grid with tiles beyond 32768. It's the one bit `pack_entry` leaves
+// free.
+#[cfg(feature = "parallel")]
+const EXT_FLAG: u64 = 1 << 11;
+
+// Packs an entry into 64 bits. Expects a quantized headroom. Never returns 0, which marks an
+// empty slot.
+#[cfg(feature = "parallel")]
+fn pack_entry(entry: Entry) -> u64 {
+    u64::from(entry.eval.to_bits()) << 32
+        | u64::from(entry.headroom.to_bits() & 0xFFFF_0000)
+        | u64::from(entry.depth & 0xF) << 12
+        | u64::from(entry.symmetry & 0x7) << 8
+        | u64::from(entry.generation)
+}
+
+#[cfg(feature = "parallel")]
+fn unpack_entry(data: u64) -> Entry {
+    Entry {
+        eval: f32::from_bits((data >> 32) as u32),
+        headroom: f32::from_bits(((data >> 16) as u32 & 0xFFFF) << 16),
+        depth: (data >> 12) as u8 & 0xF,
+        symmetry: (data >> 8) as u8 & 0x7,
+        generation: data as u8,
+    }
+}
+
+// Everything the threads of a search share, besides the position
+struct Context<'a, H> {
     min_probability: f32,
     game_engine: GameEngine,
-    heuristic: Heuristic,
+    heuristic: &'a H,
+    monitor: &'a Monitor<'a>,
+    keys: Keys,
+}
+
+// How table entries are keyed, tagged and shared
+#[derive(Clone, Copy, Debug)]
+struct Keys {
+    // Number of the search, for aging
+    generation: u8,
+    // Whether tables are keyed by `Grid::canonical`
+    canonical: bool,
+    // Whether the search stays on one thread, so that the table fills up in the same order
+    // every time
+    #[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+    deterministic: bool,
+}
+
+struct SearchState<'a, H> {
+    table: TableRef<'a>,
+    stats: SearchStats,
+    context: &'a Context<'a, H>,
+    reported_nodes: u32,
+    aborted: bool,
+}
+
+impl<'a, H: Heuristic> SearchState<'a, H> {
+    fn new(context: &'a Context<'a, H>, table: TableRef<'a>) -> Self {
+        SearchState {
+            table,
+            stats: SearchStats::default(),
+            context,
+            reported_nodes: 0,
+            aborted: false,
+        }
+    }
+
+    // Counts a node. Looking at the clock and the other threads on every node would be too
+    // slow, so that only happens every few thousand.
+    fn visit(&mut self) -> bool {
+        self.stats.nodes += 1;
+        if !self.aborted && self.stats.nodes & (CHECK_INTERVAL - 1) == 0 {
+            self.flush_nodes();
+            self.aborted = self.context.monitor.should_stop();
+            if self.stats.nodes & (PROGRESS_INTERVAL - 1) == 0 {
+                self.context.monitor.report();
+            }
+        }
+        self.aborted
+    }
+
+    fn flush_nodes(&mut self) {
+        self.context
+            .monitor
+            .add_nodes(self.stats.nodes - self.reported_nodes);
+        self.reported_nodes = self.stats.nodes;
+    }
+
+    fn lookup(&mut self, key: Grid, symmetry: u8, headroom: f32, depth: u8) -> Option<f32> {
+        let mut entry = self.table.get(key)?;
+        if !entry.covers(headroom, depth) {
+            return None;
+        }
+        self.stats.cache_hits += 1;
+        if entry.symmetry != symmetry {
+            self.stats.symmetric_hits += 1;
+        }
+        if entry.generation != self.context.keys.generation {
+            self.stats.reused_hits += 1;
+            entry.generation = self.context.keys.generation;
+            self.table.insert(key, entry);
+        }
+        Some(entry.eval)
+    }
 }
 
+// Both must be powers of two
+const CHECK_INTERVAL: u32 = 4096;
+const PROGRESS_INTERVAL: u32 = 1 << 18;
+
+// With the `parallel` feature, chance nodes this far above the cutoff probability search their
+// children on several threads. Below that, subtrees are too small to be worth it.
+#[cfg(feature = "parallel")]
+const SPLIT_HEADROOM: f32 = 50.0;
+
 /// Minimum variable depth
 pub const MIN_DEPTH: u8 = 3;
 /// Maximum variable depth
 pub const MAX_DEPTH: u8 = 14;
 
-const PROBABILITY_OF2: f32 = 0.9;
-const PROBABILITY_OF4: f32 = 0.1;
+/// How deep `Searcher` searches go, in player moves. The cutoff probability can stop a search
+/// before that.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthLimit {
+    /// Deeper as the grid gets more distinct tiles, between `MIN_DEPTH` and the given depth
+    Variable(u8),
+    /// Always the given depth, up to `MAX_DEPTH`
+    Fixed(u8),
+}
+
+impl Default for DepthLimit {
+    fn default() -> Self {
+        DepthLimit::Variable(MAX_DEPTH)
+    }
+}
+
+impl DepthLimit {
+    /// The depth of a search from `grid`
+    pub fn depth(self, grid: Grid) -> u8 {
+        self.depth_for(grid.count_distinct_tiles(), grid.biggest_tile())
+    }
+
+    // The depth of a search from a position with these tiles, whatever the size of the board
+    pub(crate) fn depth_for(self, distinct_tiles: u8, biggest_tile: u32) -> u8 {
+        let depth = match self {
+            DepthLimit::Variable(max) => variable_depth(distinct_tiles, biggest_tile).min(max),
+            DepthLimit::Fixed(depth) => depth,
+        };
+        num::clamp(depth, 1, MAX_DEPTH)
+    }
+}
 
 /// Investigate a game state and determine move evaluations.
 /// The search will stop recursing into child nodes as soon as a position at least as improbably as `min_probability` is reached.
 pub fn search(grid: Grid, min_probability: f32) -> SearchResult {
-    let depth = calculate_depth(grid);
-    search_inner(grid, depth, min_probability)
+    search_with_rule(grid, min_probability, SpawnRule::default())
+}
+
+/// Same as `search`, but expects new tiles to spawn according to `spawn_rule`.
+pub fn search_with_rule(grid: Grid, min_probability: f32, spawn_rule: SpawnRule) -> SearchResult {
+    search_with_heuristic(grid, min_probability, spawn_rule, &RowHeuristic::new())
+}
+
+/// Same as `search_with_rule`, but evaluates leaf positions with a custom `heuristic`.
+pub fn search_with_heuristic<H: Heuristic>(
+    grid: Grid,
+    min_probability: f32,
+    spawn_rule: SpawnRule,
+    heuristic: &H,
+) -> SearchResult {
+    search_controlled(
+        grid,
+        min_probability,
+        spawn_rule,
+        heuristic,
+        &SearchControl::new(),
+    )
+    .expect("A search that can't be cancelled always completes")
+}
+
+/// Same as `search_with_heuristic`, but can be cancelled and reports its progress through
+/// `control`. Returns `None` if the search was cancelled.
+pub fn search_controlled<H: Heuristic>(
+    grid: Grid,
+    min_probability: f32,
+    spawn_rule: SpawnRule,
+    heuristic: &H,
+    control: &SearchControl,
+) -> Option<SearchResult> {
+    let game_engine = GameEngine::with_spawn_rule(spawn_rule);
+    let monitor = Monitor::new(control, None, calculate_depth(grid));
+    let context = Context {
+        min_probability,
+        game_engine,
+        heuristic,
+        monitor: &monitor,
+        keys: Keys {
+            generation: 0,
+            canonical: false,
+            deterministic: false,
+        },
+    };
+    let mut table = Table::for_search(min_probability);
+    let mut result = search_inner(grid, &context, &mut table)?;
+    result.stats.cache_size = table.len() as u32;
+    Some(result)
+}
+
+/// Same as `search_with_heuristic`, but also explains the result.
+pub fn explain_with_heuristic<H: Heuristic>(
+    grid: Grid,
+    min_probability: f32,
+    spawn_rule: SpawnRule,
+    heuristic: &H,
+) -> Explanation {
+    let game_engine = GameEngine::with_spawn_rule(spawn_rule);
+    let control = SearchControl::new();
+    let monitor = Monitor::new(&control, None, calculate_depth(grid));
+    let context = Context {
+        min_probability,
+        game_engine,
+        heuristic,
+        monitor: &monitor,
+        keys: Keys {
+            generation: 0,
+            canonical: false,
+            deterministic: false,
+        },
+    };
+    let mut table = Table::for_search(min_probability);
+    let mut result = search_inner(grid, &context, &mut table)
+        .expect("A search that can't be cancelled always completes");
+    result.stats.cache_size = table.len() as u32;
+    explain_inner(result, &context, &mut table)
+}
+
+/// Investigate a game state for no longer than `budget`, give or take a fraction of a
+/// millisecond. The search is repeated with increasing depth and decreasing cutoff probability,
+/// and the result of the deepest search that completed in time is returned. The shallowest
+/// search always completes, so there is a result even if the budget is tiny.
+pub fn search_timed(grid: Grid, budget: Duration) -> SearchResult {
+    search_timed_with_heuristic(grid, budget, SpawnRule::default(), &RowHeuristic::new())
+}
+
+/// Same as `search_timed`, but with a custom `spawn_rule` and `heuristic`.
+pub fn search_timed_with_heuristic<H: Heuristic>(
+    grid: Grid,
+    budget: Duration,
+    spawn_rule: SpawnRule,
+    heuristic: &H,
+) -> SearchResult {
+    search_timed_controlled(grid, budget, spawn_rule, heuristic, &SearchControl::new())
+        .expect("A search that can't be cancelled always completes")
+}
+
+/// Same as `search_timed_with_heuristic`, but can be cancelled and reports its progress through
+/// `control`. Once cancelled, returns the deepest search completed so far, or `None` if not
+/// even the shallowest one has.
+pub fn search_timed_controlled<H: Heuristic>(
+    grid: Grid,
+    budget: Duration,
+    spawn_rule: SpawnRule,
+    heuristic: &H,
+    control: &SearchControl,
+) -> Option<SearchResult> {
+    let game_engine = GameEngine::with_spawn_rule(spawn_rule);
+    let mut table = Table::for_search(timed_min_probability(MAX_DEPTH));
+    let keys = Keys {
+        generation: 0,
+        canonical: false,
+        deterministic: false,
+    };
+    let mut result = search_deepening(
+        grid,
+        budget,
+        game_engine,
+        heuristic,
+        control,
+        &mut table,
+        keys,
+    )?;
+    result.stats.cache_size = table.len() as u32;
+    Some(result)
+}
+
+/// Default for `Searcher::with_memory_limit`
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+cfg_if! {
+    if #[cfg(feature = "parallel")] {
+        const ENTRY_SIZE: usize = std::mem::size_of::<Slot>() + std::mem::size_of::<AtomicU32>();
+    } else {
+        // Approximately, as hash tables keep some spare room
+        const ENTRY_SIZE: usize = std::mem::size_of::<(Grid, Entry)>() + 1;
+    }
+}
+
+/// Searches positions one after another and keeps the transposition table between searches,
+/// so that positions already evaluated deeply enough by an earlier search are not searched
+/// again. Works best when fed the positions of a single game in order.
+#[derive(Clone, Debug)]
+pub struct Searcher<H = RowHeuristic> {
+    table: Table,
+    memory_limit: usize,
+    generation: u8,
+    canonical: bool,
+    deterministic: bool,
+    depth_limit: DepthLimit,
+    game_engine: GameEngine,
+    heuristic: H,
+}
+
+impl Default for Searcher {
+    fn default() -> Self {
+        Searcher::with_heuristic(RowHeuristic::new())
+    }
+}
+
+impl Searcher {
+    /// Initializes a searcher with the default heuristic
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<H: Heuristic> Searcher<H> {
+    /// Initializes a searcher that evaluates leaf positions with `heuristic`
+    pub fn with_heuristic(heuristic: H) -> Self {
+        Searcher {
+            table: Table::with_memory_limit(DEFAULT_MEMORY_LIMIT),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            generation: 0,
+            canonical: false,
+            deterministic: false,
+            depth_limit: DepthLimit::default(),
+            game_engine: GameEngine::new(),
+            heuristic,
+        }
+    }
+
+    /// Expects new tiles to spawn according to `spawn_rule`. Clears the table, as the old
+    /// entries were evaluated with different odds.
+    pub fn with_spawn_rule(self, spawn_rule: SpawnRule) -> Self {
+        Searcher {
+            table: Table::with_memory_limit(self.memory_limit),
+            game_engine: GameEngine::with_spawn_rule(spawn_rule),
+            ..self
+        }
+    }
+
+    /// Keeps the table within roughly `bytes` of memory. Without the `parallel` feature, the
+    /// table can grow past that while a search is in progress. With it, the table takes all of
+    /// that memory up front, though the OS only hands out the pages that are actually used.
+    pub fn with_memory_limit(self, bytes: usize) -> Self {
+        Searcher {
+            table: Table::with_memory_limit(bytes),
+            memory_limit: bytes,
+            ..self
+        }
+    }
+
+    /// Whether to key the table by `Grid::canonical`, so that rotations and reflections of a
+    /// position share an entry. Has no effect unless the heuristic is symmetric. Off by default,
+    /// as symmetric positions rarely come up in the same search, and canonicalization makes
+    /// every lookup slower. Clears the table, as the old entries are keyed differently.
+    pub fn with_canonical_keys(self, canonical: bool) -> Self {
+        Searcher {
+            table: Table::with_memory_limit(self.memory_limit),
+            canonical,
+            ..self
+        }
+    }
+
+    /// Whether to search on a single thread. With the `parallel` feature, threads share the table
+    /// and see each other's entries in no fixed order, so evaluations vary slightly from run to
+    /// run, and a seeded game doesn't always replay move for move. A deterministic searcher
+    /// always evaluates the same positions the same way, which is best for games played side by
+    /// side on all cores anyway. Makes no difference without the feature. Off by default.
+    pub fn with_deterministic(self, deterministic: bool) -> Self {
+        Searcher {
+            deterministic,
+            ..self
+        }
+    }
+
+    /// Limits the depth of searches with a cutoff probability. Timed searches pick their own
+    /// depth.
+    pub fn with_depth_limit(self, depth_limit: DepthLimit) -> Self {
+        Searcher {
+            depth_limit,
+            ..self
+        }
+    }
+
+    /// The heuristic leaf positions are evaluated with
+    pub fn heuristic(&self) -> &H {
+        &self.heuristic
+    }
+
+    /// Number of positions kept from earlier searches
+    pub fn cache_size(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Forgets all positions kept from earlier searches, for example when a new game starts
+    pub fn clear(&mut self) {
+        self.table = Table::with_memory_limit(self.memory_limit);
+    }
+
+    /// Same as `searcher::search`, but reuses the work of earlier searches
+    pub fn search(&mut self, grid: Grid, min_probability: f32) -> SearchResult {
+        self.search_controlled(grid, min_probability, &SearchControl::new())
+            .expect("A search that can't be cancelled always completes")
+    }
+
+    /// Same as `searcher::search_controlled`, but reuses the work of earlier searches
+    pub fn search_controlled(
+        &mut self,
+        grid: Grid,
+        min_probability: f32,
+        control: &SearchControl,
+    ) -> Option<SearchResult> {
+        self.generation = self.generation.wrapping_add(1);
+        let depth = self.depth_limit.depth(grid);
+        let monitor = Monitor::new(control, None, depth);
+        let context = Context {
+            min_probability,
+            game_engine: self.game_engine,
+            heuristic: &self.heuristic,
+            monitor: &monitor,
+            keys: self.keys(),
+        };
+        let result = search_inner_to_depth(grid, depth, &context, &mut self.table);
+        self.finish(grid, result)
+    }
+
+    /// Same as `search`, but also explains the result. Following the line of play takes a
+    /// fraction of the time of the search, as most positions along it are still in the table.
+    pub fn explain(&mut self, grid: Grid, min_probability: f32) -> Explanation {
+        let result = self.search(grid, min_probability);
+        let control = SearchControl::new();
+        let monitor = Monitor::new(&control, None, result.depth);
+        let context = Context {
+            min_probability,
+            game_engine: self.game_engine,
+            heuristic: &self.heuristic,
+            monitor: &monitor,
+            keys: self.keys(),
+        };
+        explain_inner(result, &context, &mut self.table)
+    }
+
+    /// Same as `searcher::search_timed`, but reuses the work of earlier searches
+    pub fn search_timed(&mut self, grid: Grid, budget: Duration) -> SearchResult {
+        self.search_timed_controlled(grid, budget, &SearchControl::new())
+            .expect("A search that can't be cancelled always completes")
+    }
+
+    /// Same as `searcher::search_timed_controlled`, but reuses the work of earlier searches
+    pub fn search_timed_controlled(
+        &mut self,
+        grid: Grid,
+        budget: Duration,
+        control: &SearchControl,
+    ) -> Option<SearchResult> {
+        self.generation = self.generation.wrapping_add(1);
+        let keys = self.keys();
+        let result = search_deepening(
+            grid,
+            budget,
+            self.game_engine,
+            &self.heuristic,
+            control,
+            &mut self.table,
+            keys,
+        );
+        self.finish(grid, result)
+    }
+
+    fn keys(&self) -> Keys {
+        Keys {
+            generation: self.generation,
+            canonical: self.canonical && self.heuristic.is_symmetric(),
+            deterministic: self.deterministic,
+        }
+    }
+
+    fn finish(&mut self, root_grid: Grid, result: Option<SearchResult>) -> Option<SearchResult> {
+        let capacity = self.memory_limit / ENTRY_SIZE;
+        self.table.trim(root_grid, self.generation, capacity);
+        let mut result = result?;
+        result.stats.cache_size = self.table.len() as u32;
+        Some(result)
+    }
+}
+
+fn search_deepening<H: Heuristic>(
+    grid: Grid,
+    budget: Duration,
+    game_engine: GameEngine,
+    heuristic: &H,
+    control: &SearchControl,
+    table: &mut Table,
+    keys: Keys,
+) -> Option<SearchResult> {
+    let start = Instant::now();
+    // A budget too big to add to the clock never runs out
+    let deadline = start.checked_add(budget);
+
+    let mut run = |depth, deadline| {
+        let monitor = Monitor::new(control, deadline, depth);
+        let context = Context {
+            min_probability: timed_min_probability(depth),
+            game_engine,
+            heuristic,
+            monitor: &monitor,
+            keys,
+        };
+        search_inner_to_depth(grid, depth, &context, table)
+    };
+
+    let mut best = run(1, None)?;
+    let mut previous_duration: Option<Duration> = None;
+    let mut last_duration = start.elapsed();
+
+    for depth in 2..=MAX_DEPTH {
+        if best.best_move.is_none() {
+            break;
+        }
+
+        // Every iteration takes several times longer than the one before it. If the next one
+        // is not going to finish in time anyway, don't waste time starting it.
+        let growth = match previous_duration {
+            Some(previous) if previous > Duration::from_micros(1) => num::clamp(
+                last_duration.as_secs_f32() / previous.as_secs_f32(),
+                2.0,
+                16.0,
+            ),
+            _ => 2.0,
+        };
+        if start.elapsed() + last_duration.mul_f32(growth) > budget {
+            break;
+        }
+
+        let iteration_start = Instant::now();
+        match run(depth, deadline) {
+            Some(result) => best = result,
+            None => break,
+        }
+        previous_duration = Some(last_duration);
+        last_duration = iteration_start.elapsed();
+    }
+
+    Some(best)
+}
+
+// Every level of chance nodes divides the probability by the number of empty cells, so the
+// cutoff has to go down together with depth for the extra depth to be of any use.
+fn timed_min_probability(depth: u8) -> f32 {
+    10f32.powf(-f32::from(depth) / 2.0)
 }
 
 fn calculate_depth(grid: Grid) -> u8 {
-    let stage_adjustment = match grid.biggest_tile() {
+    variable_depth(grid.count_distinct_tiles(), grid.biggest_tile())
+}
+
+fn variable_depth(distinct_tiles: u8, biggest_tile: u32) -> u8 {
+    let stage_adjustment = match biggest_tile {
         x if x > 8192 => 0,
         x if x > 4096 => 1,
         _ => 2,
     };
-    let depth = grid.count_distinct_tiles().saturating_sub(stage_adjustment);
+    let depth = distinct_tiles.saturating_sub(stage_adjustment);
     num::clamp(depth, MIN_DEPTH, MAX_DEPTH)
 }
 
-#[cfg(not(feature = "parallel"))]
-fn search_inner(root_grid: Grid, depth: u8, min_probability: f32) -> SearchResult {
-    let game_engine = GameEngine::new();
-    let heuristic = Heuristic::new();
-    let mut state = SearchState {
-        min_probability,
-        ..SearchState::default()
-    };
-    let mut move_evaluations = game_engine
+fn search_inner<H: Heuristic>(
+    root_grid: Grid,
+    context: &Context<H>,
+    table: &mut Table,
+) -> Option<SearchResult> {
+    search_inner_to_depth(root_grid, calculate_depth(root_grid), context, table)
+}
+
+// Returns `None` if the search was stopped by the deadline or cancelled.
+fn search_inner_to_depth<H: Heuristic>(
+    root_grid: Grid,
+    depth: u8,
+    context: &Context<H>,
+    table: &mut Table,
+) -> Option<SearchResult> {
+    #[cfg(feature = "parallel")]
+    {
+        if !context.keys.deterministic {
+            return search_parallel(root_grid, depth, context, table);
+        }
+    }
+
+    let mut state = SearchState::new(context, table);
+    let mut move_evaluations = context
+        .game_engine
         .player_moves(root_grid)
         .map(|(m, g)| {
             let eval = player_move_eval(g, 1.0f32, depth, &mut state);
+            if !state.aborted {
+                state.flush_nodes();
+                context.monitor.finish_move(m, eval);
+            }
             (m, eval)
         })
         .collect::<Vec<_>>();
 
+    if state.aborted {
+        return None;
+    }
+
     move_evaluations.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("Failed to sort evaluations"));
 
     let best_move = move_evaluations.iter().cloned().next().map(|(mv, _)| mv);
 
     let move_evaluations = move_evaluations.into_iter().collect();
 
-    state.stats.cache_size = state.cache.len() as u32;
+    state.stats.cache_size = state.table.len() as u32;
 
-    SearchResult {
+    Some(SearchResult {
         stats: state.stats,
         root_grid,
         move_evaluations,
         best_move,
         depth,
-    }
+        min_probability: context.min_probability,
+    })
 }
 
+// Searches every root move on a thread of its own
 #[cfg(feature = "parallel")]
-fn search_inner(root_grid: Grid, depth: u8, min_probability: f32) -> SearchResult {
+fn search_parallel<H: Heuristic>(
+    root_grid: Grid,
+    depth: u8,
+    context: &Context<H>,
+    table: &mut Table,
+) -> Option<SearchResult> {
     use rayon::prelude::*;
 
-    let game_engine = GameEngine::new();
-    let mut move_evaluations = game_engine
+    let table = &*table;
+    let mut move_evaluations = context
+        .game_engine
         .player_moves(root_grid)
         .collect::<Vec<_>>()
         .par_iter()
-        .map(|(m, g)| {
-            let mut state = SearchState {
-                min_probability,
-                ..SearchState::default()
-            };
-            let eval = player_move_eval(*g, 1.0f32, depth, &mut state);
-            state.stats.cache_size = state.cache.len() as u32;
-            (*m, eval, state.stats)
+        .map(|&(m, g)| {
+            let mut state = SearchState::new(context, table);
+            let eval = player_move_eval(g, 1.0f32, depth, &mut state);
+            if !state.aborted {
+                state.flush_nodes();
+                context.monitor.finish_move(m, eval);
+            }
+            (m, eval, state.stats, state.aborted)
         })
         .collect::<Vec<_>>();
 
-    let stats = move_evaluations
+    if move_evaluations.iter().any(|&(_, _, _, aborted)| aborted) {
+        return None;
+    }
+
+    let mut stats = move_evaluations
         .iter()
-        .map(|(_, _, stats)| stats.clone())
+        .map(|(_, _, stats, _)| stats.clone())
         .fold(SearchStats::default(), |a, b| a + b);
+    stats.cache_size = table.len() as u32;
 
     move_evaluations.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("Failed to sort evaluations"));
 
-    let best_move = move_evaluations.iter().map(|(mv, _, _)| *mv).next();
+    let best_move = move_evaluations.iter().map(|(mv, _, _, _)| *mv).next();
 
     let move_evaluations = move_evaluations
         .into_iter()
-        .map(|(mv, eval, _)| (mv, eval))
+        .map(|(mv, eval, _, _)| (mv, eval))
         .collect();
 
-    SearchResult {
+    Some(SearchResult {
         root_grid,
         depth,
+        min_probability: context.min_probability,
         stats,
         move_evaluations,
         best_move,
-    }
+    })
 }
 
-fn random_move_eval(grid: Grid, probability: f32, depth: u8, state: &mut SearchState) -> f32 {
-    state.stats.nodes += 1;
+fn random_move_eval<H: Heuristic>(
+    grid: Grid,
+    probability: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> f32 {
+    if state.visit() {
+        return 0f32;
+    }
+
     state.stats.average += 1;
 
     state
+        .context
         .game_engine
         .player_moves(grid)
         .map(|(_, g)| player_move_eval(g, probability, depth, state))
         .fold(0f32, f32::max)
 }
 
-fn player_move_eval(grid: Grid, probability: f32, depth: u8, state: &mut SearchState) -> f32 {
-    state.stats.nodes += 1;
+fn player_move_eval<H: Heuristic>(
+    grid: Grid,
+    probability: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> f32 {
+    if state.visit() {
+        return 0f32;
+    }
 
-    if depth == 0 || probability < state.min_probability {
+    if depth == 0 || probability < state.context.min_probability {
         state.stats.evals += 1;
-        return state.heuristic.eval(grid);
+        return state.context.heuristic.eval(grid);
     }
 
-    if let Some(&(stored_probability, eval)) = state.cache.get(&grid) {
-        if probability <= stored_probability {
-            state.stats.cache_hits += 1;
-            return eval;
-        }
+    let headroom = quantize_headroom(probability / state.context.min_probability);
+    let (key, symmetry) = if state.context.keys.canonical {
+        grid.canonical_with_symmetry()
+    } else {
+        (grid, 0)
+    };
+    if let Some(eval) = state.lookup(key, symmetry, headroom, depth) {
+        return eval;
     }
 
     state.stats.average += 1;
 
     let count = grid.count_empty() as f32;
 
-    let prob2 = probability * PROBABILITY_OF2 / count;
-    let prob4 = probability * PROBABILITY_OF4 / count;
+    let spawn_rule = state.context.game_engine.spawn_rule();
+    let mut eval = 0f32;
+    for &(tile, tile_probability) in spawn_rule.log_tiles() {
+        let prob = probability * tile_probability / count;
+        let sum = spawn_eval(grid, tile, prob, headroom, depth, state);
+        let avg = sum / count;
+        eval += avg * tile_probability;
+    }
 
-    let sum_with2 = state
-        .game_engine
-        .random_moves_with2(grid)
-        .map(|g| random_move_eval(g, prob2, depth - 1, state))
-        .sum::<f32>();
-    let avg_with2 = sum_with2 / count;
+    // A subtree cut short is worthless, and caching it would poison later lookups
+    if !state.aborted {
+        let entry = Entry {
+            headroom,
+            depth,
+            generation: state.context.keys.generation,
+            symmetry,
+            eval,
+        };
+        state.table.insert(key, entry);
+    }
+
+    eval
+}
+
+// Follows the principal variation of a finished search, which must have used the same context
+// and table, so that the evaluations along the way match the ones of the search.
+fn explain_inner<'a, H: Heuristic>(
+    result: SearchResult,
+    context: &'a Context<'a, H>,
+    table: &'a mut Table,
+) -> Explanation {
+    let mut state = SearchState::new(context, table);
+    let game_engine = context.game_engine;
+    let &(tile, tile_probability) = game_engine
+        .spawn_rule()
+        .log_tiles()
+        .iter()
+        .fold(
+            None,
+            |likeliest: Option<&(u8, f32)>, spawn| match likeliest {
+                Some(likeliest) if likeliest.1 >= spawn.1 => Some(likeliest),
+                _ => Some(spawn),
+            },
+        )
+        .expect("A spawn rule has at least one tile");
+
+    let mut principal_variation = Vec::new();
+    let mut leaf = result.root_grid;
+    let mut best = result.best_move.map(|mv| {
+        let moved = game_engine.make_move(result.root_grid, mv);
+        (mv, moved, result.move_evaluations[&mv])
+    });
+    let mut probability = 1f32;
+    let mut depth = result.depth;
+    while let Some((mv, moved, eval)) = best.take() {
+        leaf = moved;
+        let mut spawn = None;
+        if depth > 0 && probability >= context.min_probability {
+            let count = moved.count_empty() as f32;
+            let prob = probability * tile_probability / count;
+            // Game over counts as 0, same as in the search
+            let worst = game_engine
+                .random_moves_with(moved, tile)
+                .map(|g| (g, best_reply(g, prob, depth - 1, &mut state)))
+                .min_by(|a, b| {
+                    let a = a.1.map_or(0f32, |reply| reply.2);
+                    let b = b.1.map_or(0f32, |reply| reply.2);
+                    a.partial_cmp(&b).expect("Failed to compare evaluations")
+                });
+            if let Some((spawned, reply)) = worst {
+                spawn = Spawn::between(moved, spawned).map(|s| (s, tile_probability / count));
+                leaf = spawned;
+                best = reply;
+                probability = prob;
+                depth -= 1;
+            }
+        }
+        principal_variation.push(Ply { mv, eval, spawn });
+    }
 
-    let sum_with4 = state
+    let game_over_probability = match result.best_move {
+        Some(mv) => {
+            let moved = game_engine.make_move(result.root_grid, mv);
+            game_over_probability(moved, 1f32, result.depth, &mut state)
+        }
+        None => 1f32,
+    };
+
+    Explanation {
+        features: context.heuristic.features(leaf),
+        result,
+        principal_variation,
+        leaf,
+        game_over_probability,
+    }
+}
+
+// The move the search rates best from `grid`, as the search would pick it
+fn best_reply<H: Heuristic>(
+    grid: Grid,
+    probability: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> Option<(Move, Grid, f32)> {
+    let game_engine = state.context.game_engine;
+    game_engine
+        .player_moves(grid)
+        .map(|(mv, g)| (mv, g, player_move_eval(g, probability, depth, state)))
+        .fold(None, |best, reply| match best {
+            Some(best) if best.2 >= reply.2 => Some(best),
+            _ => Some(reply),
+        })
+}
+
+// The chance of game over within the horizon after the player moved to `grid`, if every move
+// from then on is the one the search rates best
+fn game_over_probability<H: Heuristic>(
+    grid: Grid,
+    probability: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> f32 {
+    if depth == 0 || probability < state.context.min_probability {
+        return 0f32;
+    }
+
+    let game_engine = state.context.game_engine;
+    let spawn_rule = game_engine.spawn_rule();
+    let count = grid.count_empty() as f32;
+    let mut chance = 0f32;
+    for &(tile, tile_probability) in spawn_rule.log_tiles() {
+        let prob = probability * tile_probability / count;
+        for spawned in game_engine.random_moves_with(grid, tile) {
+            let lost = match best_reply(spawned, prob, depth - 1, state) {
+                Some((_, moved, _)) => game_over_probability(moved, prob, depth - 1, state),
+                None => 1f32,
+            };
+            chance += lost * tile_probability / count;
+        }
+    }
+    chance
+}
+
+// Sums the evaluations of every way `tile` can spawn on `grid`
+#[cfg(not(feature = "parallel"))]
+fn spawn_eval<H: Heuristic>(
+    grid: Grid,
+    tile: u8,
+    probability: f32,
+    _headroom: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> f32 {
+    state
+        .context
         .game_engine
-        .random_moves_with4(grid)
-        .map(|g| random_move_eval(g, prob4, depth - 1, state))
-        .sum::<f32>();
-    let avg_with4 = sum_with4 / count;
+        .random_moves_with(grid, tile)
+        .map(|g| random_move_eval(g, probability, depth - 1, state))
+        .sum::<f32>()
+}
 
-    let eval = avg_with2 * PROBABILITY_OF2 + avg_with4 * PROBABILITY_OF4;
+// Sums the evaluations of every way `tile` can spawn on `grid`. Big subtrees are searched in
+// parallel, each with a state of its own that's merged back afterwards, unless the search has to
+// be deterministic.
+#[cfg(feature = "parallel")]
+fn spawn_eval<H: Heuristic>(
+    grid: Grid,
+    tile: u8,
+    probability: f32,
+    headroom: f32,
+    depth: u8,
+    state: &mut SearchState<H>,
+) -> f32 {
+    use rayon::prelude::*;
 
-    state.cache.insert(grid, (probability, eval));
+    let children = state.context.game_engine.random_moves_with(grid, tile);
+    if headroom < SPLIT_HEADROOM || depth < 2 || state.context.keys.deterministic {
+        return children
+            .map(|g| random_move_eval(g, probability, depth - 1, state))
+            .sum::<f32>();
+    }
 
-    eval
+    let context = state.context;
+    let table = state.table;
+    let results = children
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|&g| {
+            let mut child = SearchState::new(context, table);
+            let eval = random_move_eval(g, probability, depth - 1, &mut child);
+            child.flush_nodes();
+            (eval, child.stats, child.aborted)
+        })
+        .collect::<Vec<_>>();
+
+    let mut sum = 0f32;
+    for (eval, stats, aborted) in results {
+        sum += eval;
+        // Children report their nodes to the monitor themselves
+        state.reported_nodes += stats.nodes;
+        state.stats = state.stats.clone() + stats;
+        state.aborted |= aborted;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed_search_stays_within_budget() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let budget = Duration::from_millis(50);
+        let heuristic = RowHeuristic::new();
+
+        let start = Instant::now();
+        let result = search_timed_with_heuristic(grid, budget, SpawnRule::default(), &heuristic);
+        let elapsed = start.elapsed();
+
+        assert!(result.best_move.is_some());
+        assert!(result.depth >= 1);
+        assert_eq!(result.min_probability, timed_min_probability(result.depth));
+        // Deadline is only checked every few thousand nodes, so allow some slack
+        assert!(elapsed < budget * 2, "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn timed_search_with_endless_budget_runs_until_cancelled() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let polls = AtomicU32::new(0);
+        let cancel_check = || polls.fetch_add(1, Ordering::Relaxed) >= 100;
+        let control = SearchControl::new().with_cancel_check(&cancel_check);
+
+        let result = search_timed_controlled(
+            grid,
+            Duration::MAX,
+            SpawnRule::default(),
+            &RowHeuristic::new(),
+            &control,
+        );
+
+        assert!(result.unwrap().best_move.is_some());
+    }
+
+    #[test]
+    fn timed_search_of_lost_game_has_no_move() {
+        let grid =
+            Grid::from_human([[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [4, 2, 4, 2]]).unwrap();
+
+        let result = search_timed(grid, Duration::from_millis(10));
+
+        assert_eq!(result.best_move, None);
+        assert!(result.move_evaluations.is_empty());
+    }
+
+    #[test]
+    fn cancelled_search_returns_nothing() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let token = CancellationToken::new();
+        token.cancel();
+        let control = SearchControl::new().with_token(token);
+
+        let result = search_controlled(
+            grid,
+            0.0001,
+            SpawnRule::default(),
+            &RowHeuristic::new(),
+            &control,
+        );
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn cancel_check_stops_search_in_progress() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let polls = AtomicU32::new(0);
+        let cancel_check = || polls.fetch_add(1, Ordering::Relaxed) >= 2;
+        let control = SearchControl::new().with_cancel_check(&cancel_check);
+
+        let result = search_controlled(
+            grid,
+            0.00001,
+            SpawnRule::default(),
+            &RowHeuristic::new(),
+            &control,
+        );
+
+        assert!(result.is_none());
+        assert!(polls.into_inner() > 2);
+    }
+
+    #[test]
+    fn progress_reports_every_searched_move() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let reports = Mutex::new(Vec::new());
+        let progress = |p: &SearchProgress| reports.lock().unwrap().push(p.clone());
+        let control = SearchControl::new().with_progress(&progress);
+
+        let result = search_controlled(
+            grid,
+            0.01,
+            SpawnRule::default(),
+            &RowHeuristic::new(),
+            &control,
+        )
+        .unwrap();
+
+        let reports = reports.into_inner().unwrap();
+        // Reports from different threads can arrive out of order
+        let last = reports
+            .iter()
+            .max_by_key(|p| (p.move_evaluations.len(), p.nodes))
+            .unwrap();
+        assert_eq!(last.move_evaluations, result.move_evaluations);
+        assert_eq!(last.depth, result.depth);
+        assert_eq!(last.nodes, result.stats.nodes);
+        assert!(reports.len() >= result.move_evaluations.len());
+    }
+
+    #[test]
+    fn searcher_reuses_earlier_searches() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let mut searcher = Searcher::new();
+
+        let fresh = search(grid, 0.01);
+        let first = searcher.search(grid, 0.01);
+        let second = searcher.search(grid, 0.01);
+
+        // Threads sharing a table can see each other's entries in any order, which changes the
+        // evaluations a little
+        for (mv, eval) in &fresh.move_evaluations {
+            assert!((first.move_evaluations[mv] - eval).abs() <= eval.abs() * 1e-3);
+            assert!((second.move_evaluations[mv] - eval).abs() <= eval.abs() * 1e-3);
+        }
+        assert_eq!(first.stats.reused_hits, 0);
+        assert!(second.stats.reused_hits > 0);
+        assert!(second.stats.nodes < first.stats.nodes);
+    }
+
+    #[test]
+    fn searcher_stays_within_memory_limit() {
+        let mut searcher = Searcher::new().with_memory_limit(100 * ENTRY_SIZE);
+        let game_engine = GameEngine::new();
+        let mut rng = crate::game_logic::seeded_rng(1);
+        let mut grid = Grid::default()
+            .add_random_tile_with(&mut rng)
+            .add_random_tile_with(&mut rng);
+
+        for _ in 0..20 {
+            let result = searcher.search(grid, 0.01);
+            assert!(searcher.cache_size() <= 100);
+            match result.best_move {
+                Some(mv) => {
+                    grid = game_engine
+                        .make_move(grid, mv)
+                        .add_random_tile_with(&mut rng)
+                }
+                None => break,
+            }
+        }
+    }
+
+    #[test]
+    fn searcher_keeps_to_depth_limit() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [32, 64, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+
+        let fixed = Searcher::new()
+            .with_depth_limit(DepthLimit::Fixed(2))
+            .search(grid, 0.0001);
+        let capped = Searcher::new()
+            .with_depth_limit(DepthLimit::Variable(4))
+            .search(grid, 0.0001);
+        let variable = Searcher::new().search(grid, 0.0001);
+
+        assert_eq!(fixed.depth, 2);
+        assert_eq!(capped.depth, 4);
+        assert_eq!(variable.depth, calculate_depth(grid));
+    }
+
+    #[test]
+    fn explanation_follows_best_moves() {
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [32, 64, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let mut searcher = Searcher::new().with_depth_limit(DepthLimit::Fixed(3));
+
+        let explanation = searcher.explain(grid, 0.0001);
+
+        let result = &explanation.result;
+        let line = &explanation.principal_variation;
+        assert_eq!(line.len(), 4);
+        assert_eq!(Some(line[0].mv), result.best_move);
+        assert_eq!(line[0].eval, result.move_evaluations[&line[0].mv]);
+        assert!(line[..3].iter().all(|ply| ply.spawn.is_some()));
+        assert_eq!(line[3].spawn, None);
+        let features = explanation.features.unwrap();
+        let eval = searcher.heuristic().eval(explanation.leaf);
+        assert!((features.total() - eval).abs() < 1e-3 * eval);
+        assert_eq!(explanation.game_over_probability, 0.0);
+    }
+
+    #[test]
+    fn explanation_sees_game_over_coming() {
+        // Either move leaves one empty cell, and only a 2 spawning there leaves a move after it
+        let grid =
+            Grid::from_human([[2, 8, 16, 2], [8, 2, 4, 8], [16, 8, 2, 16], [2, 16, 8, 0]]).unwrap();
+        let lost =
+            Grid::from_human([[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [4, 2, 4, 2]]).unwrap();
+
+        let explanation = Searcher::new()
+            .with_depth_limit(DepthLimit::Fixed(1))
+            .explain(grid, 0.0001);
+        let game_over =
+            explain_with_heuristic(lost, 0.0001, SpawnRule::default(), &RowHeuristic::new());
+
+        assert!((explanation.game_over_probability - 0.1).abs() < 1e-6);
+        assert!(game_over.principal_variation.is_empty());
+        assert_eq!(game_over.game_over_probability, 1.0);
+    }
+
+    #[test]
+    fn canonical_keys_share_entries_between_symmetric_grids() {
+        let grid = Grid::from_human([
+            [2, 4, 8, 16],
+            [0, 2, 2, 32],
+            [0, 0, 4, 1024],
+            [0, 0, 0, 2048],
+        ])
+        .unwrap();
+        let mut searcher = Searcher::new().with_canonical_keys(true);
+
+        let first = searcher.search(grid, 0.01);
+        let mirrored = searcher.search(grid.reflect_horizontally(), 0.01);
+
+        assert_eq!(first.stats.symmetric_hits, 0);
+        assert!(mirrored.stats.symmetric_hits > 0);
+        for (mv, eval) in &first.move_evaluations {
+            let mirrored_move = match mv {
+                Move::Left => Move::Right,
+                Move::Right => Move::Left,
+                other => *other,
+            };
+            let mirrored_eval = mirrored.move_evaluations[&mirrored_move];
+            assert!((eval - mirrored_eval).abs() <= eval.abs() * 1e-5);
+        }
+    }
+
+    #[test]
+    fn deterministic_searchers_replay_the_same_game() {
+        let play = || {
+            let game_engine = GameEngine::new();
+            let mut rng = crate::game_logic::seeded_rng(42);
+            let mut grid = Grid::default()
+                .add_random_tile_with(&mut rng)
+                .add_random_tile_with(&mut rng);
+            let mut searcher = Searcher::new()
+                .with_depth_limit(DepthLimit::Fixed(3))
+                .with_deterministic(true);
+            let mut results = Vec::new();
+            for _ in 0..30 {
+                let result = searcher.search(grid, 0.0001);
+                let mv = result.best_move.unwrap();
+                grid = game_engine
+                    .make_move(grid, mv)
+                    .add_random_tile_with(&mut rng);
+                results.push(result);
+            }
+            results
+        };
+
+        let first = play();
+        let second = play();
+
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.root_grid, b.root_grid);
+            assert_eq!(a.move_evaluations, b.move_evaluations);
+            assert_eq!(a.stats.nodes, b.stats.nodes);
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    #[test]
+    fn trim_keeps_three_quarters_of_capacity() {
+        let mut table = LocalTable::default();
+        let entry = Entry {
+            headroom: 1.0,
+            depth: 1,
+            generation: 0,
+            symmetry: 0,
+            eval: 0.0,
+        };
+        for log in 0..16 {
+            let grid = Grid::from_human([[2 << log, 0, 0, 0], [0; 4], [0; 4], [0; 4]]).unwrap();
+            table.insert(grid, entry);
+        }
+
+        table.trim(Grid::default(), 0, 12);
+
+        assert_eq!(table.len(), 9);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn shared_table_keeps_entries() {
+        let table = SharedTable::for_search(0.01);
+        let grid =
+            Grid::from_human([[2, 4, 8, 16], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2]]).unwrap();
+        let entry = Entry {
+            headroom: quantize_headroom(12.345),
+            depth: 14,
+            generation: 255,
+            symmetry: 7,
+            eval: -1234.5,
+        };
+
+        assert_eq!(table.get(grid), None);
+        table.insert(grid, entry);
+        assert_eq!(table.get(grid), Some(entry));
+        assert_eq!(table.get(grid.transpose()), None);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn shared_table_keeps_entries_of_big_tiles() {
+        let table = SharedTable::for_search(0.01);
+        let small = Grid::from_human([[2, 4, 8, 32768], [0; 4], [0; 4], [0, 0, 0, 2]]).unwrap();
+        let big = Grid::from_human([[2, 4, 8, 65536], [0; 4], [0; 4], [0, 0, 0, 2]]).unwrap();
+        let bigger = Grid::from_human([[2, 4, 8, 131_072], [0; 4], [0; 4], [0, 0, 0, 2]]).unwrap();
+        let entry = |eval| Entry {
+            headroom: 1.0,
+            depth: 3,
+            generation: 0,
+            symmetry: 0,
+            eval,
+        };
+
+        table.insert(big, entry(1.0));
+        assert_eq!(table.get(big), Some(entry(1.0)));
+        assert_eq!(table.get(small), None);
+        assert_eq!(table.get(bigger), None);
+        table.insert(small, entry(2.0));
+        table.insert(bigger, entry(3.0));
+        assert_eq!(table.get(small), Some(entry(2.0)));
+        assert_eq!(table.get(bigger), Some(entry(3.0)));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn memory_limit_rounds_down_to_power_of_two() {
+        assert_eq!(
+            SharedTable::with_memory_limit(64 * ENTRY_SIZE).slots.len(),
+            64
+        );
+        assert_eq!(
+            SharedTable::with_memory_limit(100 * ENTRY_SIZE).slots.len(),
+            64
+        );
+        assert_eq!(SharedTable::with_memory_limit(ENTRY_SIZE).slots.len(), 1);
+    }
 }
@@ -0,0 +1,221 @@
+use crate::RunResult;
+use std::io::{self, Write};
+
+// Two-sided 95% critical values of Student's t distribution for 1 to 30 degrees of freedom.
+// Past that, the normal distribution is close enough.
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+const Z_95: f64 = 1.960;
+
+/// Writes how configuration B did against configuration A, game by game. Both must have played
+/// the same seeds in the same order.
+pub fn report(a: &[RunResult], b: &[RunResult], out: &mut dyn Write) -> io::Result<()> {
+    assert!(
+        a.iter().map(|r| r.seed).eq(b.iter().map(|r| r.seed)),
+        "Both configurations must play the same seeds"
+    );
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "B - A, paired by seed, with 95% confidence intervals and two-sided p-values:"
+    )?;
+
+    writeln!(out, "Score: {}", paired(a, b, |r| f64::from(r.score)))?;
+    writeln!(out, "Moves: {}", paired(a, b, |r| f64::from(r.moves)))?;
+
+    let biggest = a.iter().chain(b).map(|r| r.biggest).max().unwrap_or(0);
+    let mut tile = 256;
+    while tile <= biggest {
+        writeln!(out, "Reached {:>6}: {}", tile, reach(a, b, tile))?;
+        tile *= 2;
+    }
+    Ok(())
+}
+
+struct Paired {
+    mean_a: f64,
+    mean_b: f64,
+    difference: f64,
+    margin: f64,
+    p: f64,
+}
+
+impl std::fmt::Display for Paired {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "A {:.1}, B {:.1}, difference {:+.1} [{:+.1}, {:+.1}], p = {:.3}",
+            self.mean_a,
+            self.mean_b,
+            self.difference,
+            self.difference - self.margin,
+            self.difference + self.margin,
+            self.p
+        )
+    }
+}
+
+// Paired t-test of a metric, or "n/a" with fewer than two games, as one game says nothing about
+// how much the difference varies
+fn paired(a: &[RunResult], b: &[RunResult], metric: impl Fn(&RunResult) -> f64) -> String {
+    if a.len() < 2 {
+        return "n/a".to_string();
+    }
+    let n = a.len() as f64;
+    let mean_a = a.iter().map(&metric).sum::<f64>() / n;
+    let mean_b = b.iter().map(&metric).sum::<f64>() / n;
+    let differences = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| metric(b) - metric(a))
+        .collect::<Vec<_>>();
+    let difference = mean_b - mean_a;
+    let variance = differences
+        .iter()
+        .map(|d| (d - difference).powi(2))
+        .sum::<f64>()
+        / (n - 1.0);
+    let error = (variance / n).sqrt();
+    let critical = T_95.get(a.len() - 2).cloned().unwrap_or(Z_95);
+    let p = if error > 0.0 {
+        t_test(difference / error, n - 1.0)
+    } else if difference == 0.0 {
+        1.0
+    } else {
+        0.0
+    };
+    Paired {
+        mean_a,
+        mean_b,
+        difference,
+        margin: critical * error,
+        p,
+    }
+    .to_string()
+}
+
+// Compares how often each configuration reached `tile`. Only the games where just one of them
+// reached it tell them apart, so the p-value is McNemar's exact test on those.
+fn reach(a: &[RunResult], b: &[RunResult], tile: u32) -> String {
+    let n = a.len() as f64;
+    let (mut only_a, mut only_b, mut reached_a, mut reached_b) = (0u64, 0u64, 0u64, 0u64);
+    for (a, b) in a.iter().zip(b) {
+        let (in_a, in_b) = (a.biggest >= tile, b.biggest >= tile);
+        reached_a += u64::from(in_a);
+        reached_b += u64::from(in_b);
+        only_a += u64::from(in_a && !in_b);
+        only_b += u64::from(in_b && !in_a);
+    }
+
+    let difference = (only_b as f64 - only_a as f64) / n;
+    let discordant = (only_a + only_b) as f64;
+    let error = (discordant - n * difference * difference).max(0.0).sqrt() / n;
+    let margin = Z_95 * error;
+    format!(
+        "A {:>5.1}%, B {:>5.1}%, difference {:+.1}% [{:+.1}%, {:+.1}%], p = {:.3} (only A: {}, only B: {})",
+        reached_a as f64 * 100.0 / n,
+        reached_b as f64 * 100.0 / n,
+        difference * 100.0,
+        (difference - margin) * 100.0,
+        (difference + margin) * 100.0,
+        sign_test(only_a, only_b),
+        only_a,
+        only_b,
+    )
+}
+
+// Two-sided exact binomial test of `x` against `y` successes, with even odds
+fn sign_test(x: u64, y: u64) -> f64 {
+    let n = x + y;
+    if n == 0 {
+        return 1.0;
+    }
+    // P(X <= min(x, y)) for X ~ Binomial(n, 1/2), summed in log space to survive big `n`
+    let ln_half_n = n as f64 * 0.5f64.ln();
+    let mut ln_choose = 0f64;
+    let mut tail = 0f64;
+    for k in 0..=x.min(y) {
+        if k > 0 {
+            ln_choose += ((n - k + 1) as f64).ln() - (k as f64).ln();
+        }
+        tail += (ln_choose + ln_half_n).exp();
+    }
+    (2.0 * tail).min(1.0)
+}
+
+// Two-sided p-value of `t` in Student's t distribution with `df` degrees of freedom
+fn t_test(t: f64, df: f64) -> f64 {
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+// Regularized incomplete beta function, by the continued fraction of Numerical Recipes
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The continued fraction converges quickly on this side of the mean only
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+// Evaluates the continued fraction of the incomplete beta function by Lentz's method
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    // Keeps the denominators away from 0
+    let nonzero = |v: f64| if v.abs() < 1e-300 { 1e-300 } else { v };
+    let mut c = 1.0;
+    let mut d = 1.0 / nonzero(1.0 - (a + b) * x / (a + 1.0));
+    let mut fraction = d;
+    for m in 1..=300 {
+        let m = f64::from(m);
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        d = 1.0 / nonzero(1.0 + even * d);
+        c = nonzero(1.0 + even / c);
+        fraction *= d * c;
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        d = 1.0 / nonzero(1.0 + odd * d);
+        c = nonzero(1.0 + odd / c);
+        fraction *= d * c;
+        if (d * c - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    fraction
+}
+
+// Natural logarithm of the gamma function for positive `x`, by the Lanczos approximation.
+// Good to about 1e-15.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.0;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    let t = x + G + 0.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
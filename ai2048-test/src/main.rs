@@ -1,52 +1,341 @@
-use ai2048_lib::game_logic::{GameEngine, Grid};
-use ai2048_lib::searcher;
+use ai2048_lib::game_logic::{self, GameEngine, Grid};
+use ai2048_lib::heuristic::{HeuristicWeights, RowHeuristic};
+use ai2048_lib::mcts::{Budget, MctsSearcher};
+use ai2048_lib::monte_carlo::RolloutAgent;
+use ai2048_lib::ntuple::NTupleNetwork;
+use ai2048_lib::record::GameRecord;
+use ai2048_lib::rollout::RolloutPolicy;
+use ai2048_lib::searcher::{DepthLimit, SearchResult, Searcher};
 use chrono::prelude::*;
 use chrono::Duration;
 use itertools::Itertools;
 use rayon::prelude::*;
-use std::sync::Mutex;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
 
-const MIN_PROBABILITY: f32 = 0.001;
-const TOTAL_RUNS: usize = 100;
+mod compare;
+mod output;
+
+use output::Format;
+
+const MCTS_ITERATIONS: u32 = 1000;
+
+/// Plays a number of seeded games in parallel and reports how well the AI did in each of them,
+/// and in all of them together.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ai2048-test")]
+struct Opt {
+    /// Number of games to play
+    #[structopt(short, long, default_value = "100", parse(try_from_str = parse_runs))]
+    runs: u64,
+    /// Seed of the first game. Every next game gets the next seed. Random if omitted
+    #[structopt(short, long)]
+    seed: Option<u64>,
+    /// Seeds of the games, separated by commas. Overrides `--runs` and `--seed`
+    #[structopt(long, conflicts_with_all = &["runs", "seed"])]
+    seeds: Option<String>,
+    /// Search algorithm
+    #[structopt(
+        short,
+        long,
+        default_value = "expectimax",
+        possible_values = &["expectimax", "mcts", "mcts-greedy", "monte-carlo"]
+    )]
+    algorithm: Algorithm,
+    /// Cutoff probability of expectimax searches
+    #[structopt(short, long, default_value = "0.001")]
+    min_probability: f32,
+    /// Search exactly this deep with expectimax, instead of deeper as the game goes on
+    #[structopt(short, long, conflicts_with = "max-depth")]
+    depth: Option<u8>,
+    /// Deepest expectimax search as the game goes on
+    #[structopt(long, default_value = "14")]
+    max_depth: u8,
+    /// Weights of the heuristic, five numbers separated by commas. Defaults if omitted
+    #[structopt(short, long, conflicts_with_all = &["weights-file", "network"])]
+    weights: Option<String>,
+    /// File to read the weights of the heuristic from, such as the one ai2048-tune writes
+    #[structopt(long, parse(from_os_str), conflicts_with = "network")]
+    weights_file: Option<PathBuf>,
+    /// File with an n-tuple network trained by ai2048-train, to evaluate expectimax's leaf
+    /// positions with instead of the heuristic
+    #[structopt(short, long, parse(from_os_str))]
+    network: Option<PathBuf>,
+    /// Number of threads to play games on. All cores if omitted
+    #[structopt(short, long)]
+    threads: Option<usize>,
+    /// Format of the result of every game
+    #[structopt(
+        short,
+        long,
+        default_value = "text",
+        possible_values = &["text", "jsonl", "csv"]
+    )]
+    format: Format,
+    /// File to write the result of every game to. Standard output if omitted, in which case the
+    /// summary goes to standard error unless the format is text
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Directory to save a record of every game to, named after the configuration and the seed,
+    /// such as `A-42.txt`
+    #[structopt(long, parse(from_os_str))]
+    record_dir: Option<PathBuf>,
+    /// Play every seed a second time with configuration B, and compare how it did against the
+    /// first one, game by game. Configuration B takes the options above unless overridden by
+    /// the `--b-*` options
+    #[structopt(long)]
+    compare: bool,
+    /// Search algorithm of configuration B
+    #[structopt(
+        long,
+        requires = "compare",
+        possible_values = &["expectimax", "mcts", "mcts-greedy", "monte-carlo"]
+    )]
+    b_algorithm: Option<Algorithm>,
+    /// Cutoff probability of configuration B
+    #[structopt(long, requires = "compare")]
+    b_min_probability: Option<f32>,
+    /// Fixed depth of configuration B
+    #[structopt(long, requires = "compare", conflicts_with = "b-max-depth")]
+    b_depth: Option<u8>,
+    /// Deepest variable depth of configuration B
+    #[structopt(long, requires = "compare")]
+    b_max_depth: Option<u8>,
+    /// Weights of the heuristic of configuration B
+    #[structopt(
+        long,
+        requires = "compare",
+        conflicts_with_all = &["b-weights-file", "b-network"]
+    )]
+    b_weights: Option<String>,
+    /// File to read the weights of the heuristic of configuration B from
+    #[structopt(
+        long,
+        requires = "compare",
+        parse(from_os_str),
+        conflicts_with = "b-network"
+    )]
+    b_weights_file: Option<PathBuf>,
+    /// File with the n-tuple network of configuration B
+    #[structopt(long, requires = "compare", parse(from_os_str))]
+    b_network: Option<PathBuf>,
+}
+
+fn parse_runs(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("At least one game is needed to report on".to_string()),
+        Ok(runs) => Ok(runs),
+        Err(e) => Err(format!("Invalid number of games: {}", e)),
+    }
+}
 
 fn main() {
-    let finished = Mutex::new(0);
-    let started = Mutex::new(0);
+    let opt = Opt::from_args();
+
+    if let Some(threads) = opt.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Failed to set up the thread pool");
+    }
+
+    let seeds = match &opt.seeds {
+        Some(seeds) => seeds
+            .split(',')
+            .map(|seed| seed.trim().parse())
+            .collect::<Result<Vec<u64>, _>>()
+            .expect("Seeds must be unsigned 64-bit integers separated by commas"),
+        None => {
+            let seed = opt.seed.unwrap_or_else(rand::random);
+            (0..opt.runs).map(|run| seed.wrapping_add(run)).collect()
+        }
+    };
+    let evaluator = read_evaluator(
+        opt.weights.as_ref(),
+        opt.weights_file.as_ref(),
+        opt.network.as_ref(),
+    )
+    .unwrap_or_else(|| Evaluator::Heuristic(RowHeuristic::new()));
+    let a = Config::new(
+        "A",
+        opt.algorithm,
+        evaluator.clone(),
+        opt.min_probability,
+        opt.depth,
+        opt.max_depth,
+    );
+    let mut configs = vec![a];
+    if opt.compare {
+        // A fixed depth of B overrides the variable depth of A, and the other way around
+        let (b_depth, b_max_depth) = match (opt.b_depth, opt.b_max_depth) {
+            (None, None) => (opt.depth, opt.max_depth),
+            (depth, max_depth) => (depth, max_depth.unwrap_or(opt.max_depth)),
+        };
+        configs.push(Config::new(
+            "B",
+            opt.b_algorithm.unwrap_or(opt.algorithm),
+            read_evaluator(
+                opt.b_weights.as_ref(),
+                opt.b_weights_file.as_ref(),
+                opt.b_network.as_ref(),
+            )
+            .unwrap_or(evaluator),
+            opt.b_min_probability.unwrap_or(opt.min_probability),
+            b_depth,
+            b_max_depth,
+        ));
+    }
+
+    let records: Box<dyn Write + Send> = match &opt.output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).expect("Failed to create the output file"),
+        )),
+        None => Box::new(io::stdout()),
+    };
+    let summary: Box<dyn Write> = if opt.output.is_none() && opt.format != Format::Text {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+
+    if let Some(dir) = &opt.record_dir {
+        std::fs::create_dir_all(dir).expect("Failed to create the record directory");
+    }
+
+    run(
+        &configs,
+        &seeds,
+        opt.format,
+        records,
+        summary,
+        opt.record_dir.as_deref(),
+    )
+    .expect("Failed to write the results");
+}
+
+// The heuristic with weights given inline or in a file, or the network read from a file. `None`
+// if none of them is given
+fn read_evaluator(
+    weights: Option<&String>,
+    weights_file: Option<&PathBuf>,
+    network: Option<&PathBuf>,
+) -> Option<Evaluator> {
+    let weights = match (weights, weights_file) {
+        (Some(weights), _) => Some(
+            HeuristicWeights::from_display(weights)
+                .expect("The weights must be five numbers separated by commas"),
+        ),
+        (None, Some(path)) => {
+            Some(HeuristicWeights::load(path).expect("Failed to read the weights file"))
+        }
+        (None, None) => None,
+    };
+    match (weights, network) {
+        (Some(weights), _) => Some(Evaluator::Heuristic(RowHeuristic::with_weights(weights))),
+        (None, Some(path)) => Some(Evaluator::Network(
+            Arc::new(NTupleNetwork::load(path).expect("Failed to read the network")),
+            path.clone(),
+        )),
+        (None, None) => None,
+    }
+}
 
-    let start = Utc::now();
+fn run(
+    configs: &[Config],
+    seeds: &[u64],
+    format: Format,
+    records: Box<dyn Write + Send>,
+    mut summary: Box<dyn Write>,
+    record_dir: Option<&Path>,
+) -> io::Result<()> {
+    let consecutive = seeds
+        .iter()
+        .tuple_windows()
+        .all(|(a, b)| a.wrapping_add(1) == *b);
+    match seeds.first() {
+        Some(first) if consecutive => writeln!(summary, "SEED: {}", first)?,
+        _ => writeln!(summary, "SEEDS: {}", seeds.iter().join(","))?,
+    }
+    writeln!(summary, "RUNS: {}", seeds.len())?;
 
-    println!("MIN_PROBABILITY: {}", MIN_PROBABILITY);
+    let records = Mutex::new(records);
+    if let Some(header) = format.header() {
+        writeln!(records.lock().unwrap(), "{}", header)?;
+    }
 
-    let mut results = (0..TOTAL_RUNS)
-        .collect::<Vec<_>>()
+    let mut all_results = Vec::new();
+    for config in configs {
+        if configs.len() > 1 {
+            writeln!(summary)?;
+            writeln!(summary, "CONFIGURATION {}", config.name)?;
+        }
+        writeln!(summary, "MIN_PROBABILITY: {}", config.min_probability)?;
+        writeln!(summary, "DEPTH: {:?}", config.depth_limit)?;
+        match &config.evaluator {
+            Evaluator::Heuristic(heuristic) => {
+                writeln!(summary, "WEIGHTS: {}", heuristic.weights())?
+            }
+            Evaluator::Network(_, path) => writeln!(summary, "NETWORK: {}", path.display())?,
+        }
+        writeln!(summary, "ALGORITHM: {}", config.algorithm.name())?;
+
+        let start = Utc::now();
+        let results = play(config, seeds, format, &records, record_dir)?;
+        summarize(&results, Utc::now() - start, &mut *summary)?;
+        all_results.push(results);
+    }
+
+    if let [a, b] = all_results.as_slice() {
+        compare::report(a, b, &mut *summary)?;
+    }
+    Ok(())
+}
+
+// Plays a game for every seed, and writes a record of every game as soon as it's over.
+// Returns the results in the order of the seeds.
+fn play(
+    config: &Config,
+    seeds: &[u64],
+    format: Format,
+    records: &Mutex<Box<dyn Write + Send>>,
+    record_dir: Option<&Path>,
+) -> io::Result<Vec<RunResult>> {
+    let finished = Mutex::new(0);
+    let started = Mutex::new(0);
+    seeds
         .par_iter()
-        .map(|_| {
+        .map(|&seed| {
             let started = {
                 let mut started = started.lock().unwrap();
                 *started += 1;
                 *started
             };
-            let run_result = run_one();
+            let run_result = run_one(seed, config);
+            if let Some(dir) = record_dir {
+                let path = dir.join(format!("{}-{}.txt", config.name, seed));
+                run_result.record.save(path)?;
+            }
             let finished = {
                 let mut finished = finished.lock().unwrap();
                 *finished += 1;
                 *finished
             };
-            println!(
-                "Result #{:>3} ({:>3}): Finished: {:>4} sec; Survived: {:>5} moves; {:>4.1} ms per move; Biggest tile: {:>5}",
-                finished,
-                started,
-                run_result.elapsed.num_seconds(),
-                run_result.moves,
-                run_result.per_move().num_microseconds().unwrap() as f64 / 1000.0,
-                run_result.biggest,
-            );
-            run_result
+            let mut records = records.lock().unwrap();
+            writeln!(records, "{}", format.record(&run_result, finished, started))?;
+            records.flush()?;
+            Ok(run_result)
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    let elapsed = Utc::now() - start;
+fn summarize(results: &[RunResult], elapsed: Duration, summary: &mut dyn Write) -> io::Result<()> {
+    let total_runs = results.len().max(1);
 
+    let mut results = results.iter().collect::<Vec<_>>();
     results.sort_by_key(|result| -i64::from(result.biggest));
     let grouped_by_biggest: Vec<(u32, usize)> = results
         .iter()
@@ -55,59 +344,250 @@ fn main() {
         .map(|(biggest, group)| (biggest, group.count()))
         .collect();
     let avg_moves =
-        results.iter().map(|result| result.moves).sum::<u32>() as f32 / TOTAL_RUNS as f32;
+        results.iter().map(|result| result.moves).sum::<u32>() as f32 / total_runs as f32;
+    let avg_score = results
+        .iter()
+        .map(|result| u64::from(result.score))
+        .sum::<u64>() as f64
+        / total_runs as f64;
+    let avg_score_per_move = results
+        .iter()
+        .map(|result| result.score_per_move())
+        .sum::<f64>()
+        / total_runs as f64;
     let avg_elapsed = results
         .iter()
         .map(|result| result.elapsed)
         .fold(Duration::zero(), |a, b| a + b)
-        / TOTAL_RUNS as i32;
+        / total_runs as i32;
+    // A hit rate of 0 rather than NaN for agents that count no nodes
+    let nodes = results
+        .iter()
+        .map(|result| result.nodes)
+        .sum::<u64>()
+        .max(1) as f64;
+    let cache_hits = results.iter().map(|result| result.cache_hits).sum::<u64>() as f64;
+    let reused_hits = results.iter().map(|result| result.reused_hits).sum::<u64>() as f64;
     let mut agg_count = 0;
     for (biggest, count) in grouped_by_biggest {
         agg_count += count;
-        println!(
+        writeln!(
+            summary,
             "{:>5}: {:>5.1}%",
             biggest,
-            (agg_count * 100) as f32 / TOTAL_RUNS as f32
-        );
+            (agg_count * 100) as f32 / total_runs as f32
+        )?;
     }
-    println!("Average moves: {}", avg_moves);
-    println!("Average duration: {}", avg_elapsed);
-    println!(
+    writeln!(summary, "Average moves: {}", avg_moves)?;
+    writeln!(summary, "Average score: {:.0}", avg_score)?;
+    writeln!(summary, "Average score per move: {:.1}", avg_score_per_move)?;
+    writeln!(summary, "Average duration: {}", avg_elapsed)?;
+    writeln!(
+        summary,
+        "Cache hit rate: {:.1}% ({:.1}% from earlier searches)",
+        cache_hits * 100.0 / nodes,
+        reused_hits * 100.0 / nodes
+    )?;
+    writeln!(
+        summary,
         "The whole test took {} min {} sec",
         elapsed.num_minutes(),
         elapsed.num_seconds() % 60
-    );
+    )?;
+    Ok(())
 }
 
-struct RunResult {
+pub struct RunResult {
+    seed: u64,
+    config: &'static str,
+    algorithm: &'static str,
     moves: u32,
     biggest: u32,
+    score: u32,
     elapsed: Duration,
+    nodes: u64,
+    cache_hits: u64,
+    reused_hits: u64,
+    record: GameRecord,
 }
 
 impl RunResult {
     fn per_move(&self) -> Duration {
         self.elapsed / (self.moves as i32)
     }
+
+    fn score_per_move(&self) -> f64 {
+        f64::from(self.score) / f64::from(self.moves)
+    }
+
+    fn hit_rate(&self) -> f64 {
+        self.cache_hits as f64 / self.nodes.max(1) as f64
+    }
+
+    fn reuse_rate(&self) -> f64 {
+        self.reused_hits as f64 / self.nodes.max(1) as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Algorithm {
+    Expectimax,
+    Mcts(RolloutPolicy),
+    MonteCarlo,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "expectimax" => Ok(Algorithm::Expectimax),
+            "mcts" => Ok(Algorithm::Mcts(RolloutPolicy::Random)),
+            "mcts-greedy" => Ok(Algorithm::Mcts(RolloutPolicy::Greedy)),
+            "monte-carlo" => Ok(Algorithm::MonteCarlo),
+            _ => Err(format!("Unknown algorithm: {}", s)),
+        }
+    }
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Expectimax => "expectimax",
+            Algorithm::Mcts(RolloutPolicy::Random) => "mcts",
+            Algorithm::Mcts(RolloutPolicy::Greedy) => "mcts-greedy",
+            Algorithm::MonteCarlo => "monte-carlo",
+        }
+    }
+}
+
+// What expectimax evaluates leaf positions with
+#[derive(Clone)]
+enum Evaluator {
+    Heuristic(RowHeuristic),
+    Network(Arc<NTupleNetwork>, PathBuf),
+}
+
+// Everything that decides how the AI plays
+struct Config {
+    name: &'static str,
+    algorithm: Algorithm,
+    evaluator: Evaluator,
+    min_probability: f32,
+    depth_limit: DepthLimit,
+}
+
+impl Config {
+    fn new(
+        name: &'static str,
+        algorithm: Algorithm,
+        evaluator: Evaluator,
+        min_probability: f32,
+        depth: Option<u8>,
+        max_depth: u8,
+    ) -> Config {
+        Config {
+            name,
+            algorithm,
+            evaluator,
+            min_probability,
+            depth_limit: match depth {
+                Some(depth) => DepthLimit::Fixed(depth),
+                None => DepthLimit::Variable(max_depth),
+            },
+        }
+    }
+}
+
+enum Agent {
+    Expectimax(Searcher, f32),
+    Network(Searcher<Arc<NTupleNetwork>>, f32),
+    Mcts(MctsSearcher),
+    MonteCarlo(RolloutAgent),
+}
+
+impl Agent {
+    // Games already keep all cores busy, so expectimax stays on one thread, where every seed
+    // replays the same game
+    fn new(config: &Config, seed: u64) -> Agent {
+        match config.algorithm {
+            Algorithm::Expectimax => match &config.evaluator {
+                Evaluator::Heuristic(heuristic) => Agent::Expectimax(
+                    Searcher::with_heuristic(heuristic.clone())
+                        .with_depth_limit(config.depth_limit)
+                        .with_deterministic(true),
+                    config.min_probability,
+                ),
+                Evaluator::Network(network, _) => Agent::Network(
+                    Searcher::with_heuristic(network.clone())
+                        .with_depth_limit(config.depth_limit)
+                        .with_deterministic(true),
+                    config.min_probability,
+                ),
+            },
+            Algorithm::Mcts(policy) => Agent::Mcts(
+                MctsSearcher::new()
+                    .with_rollout_policy(policy)
+                    .with_budget(Budget::Iterations(MCTS_ITERATIONS))
+                    .with_seed(seed),
+            ),
+            Algorithm::MonteCarlo => Agent::MonteCarlo(RolloutAgent::new().with_seed(seed)),
+        }
+    }
+
+    fn search(&mut self, grid: Grid) -> SearchResult {
+        match self {
+            Agent::Expectimax(searcher, min_probability) => searcher.search(grid, *min_probability),
+            Agent::Network(searcher, min_probability) => searcher.search(grid, *min_probability),
+            Agent::Mcts(searcher) => searcher.search(grid),
+            Agent::MonteCarlo(agent) => agent.search(grid),
+        }
+    }
 }
 
-fn run_one() -> RunResult {
+fn run_one(seed: u64, config: &Config) -> RunResult {
     let game_engine = GameEngine::new();
-    let mut grid = Grid::default().add_random_tile().add_random_tile();
+    // The searcher's own randomness must not change the tiles of the game
+    let mut agent = Agent::new(config, !seed);
+    let mut rng = game_logic::seeded_rng(seed);
+    let mut grid = Grid::default()
+        .add_random_tile_with(&mut rng)
+        .add_random_tile_with(&mut rng);
+    let mut record = GameRecord::new(grid);
     let start_overall = Utc::now();
     let mut moves = 0;
+    let mut score = 0;
+    let mut nodes = 0;
+    let mut cache_hits = 0;
+    let mut reused_hits = 0;
     loop {
         moves += 1;
-        let result = searcher::search(grid, MIN_PROBABILITY);
+        let result = agent.search(grid);
+        nodes += u64::from(result.stats.nodes);
+        cache_hits += u64::from(result.stats.cache_hits);
+        reused_hits += u64::from(result.stats.reused_hits);
         if let Some(mv) = result.best_move {
-            grid = game_engine.make_move(grid, mv).add_random_tile();
+            let outcome = game_engine.make_scored_move(grid, mv);
+            score += outcome.score;
+            grid = outcome.grid.add_random_tile_with(&mut rng);
+            record
+                .push(mv, outcome.grid, grid)
+                .expect("Exactly one tile spawns after every move");
         } else {
             let elapsed = Utc::now() - start_overall;
             let biggest = grid.biggest_tile();
             return RunResult {
+                seed,
+                config: config.name,
+                algorithm: config.algorithm.name(),
                 moves,
                 biggest,
+                score,
                 elapsed,
+                nodes,
+                cache_hits,
+                reused_hits,
+                record,
             };
         }
     }
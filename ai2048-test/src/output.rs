@@ -0,0 +1,101 @@
+use crate::RunResult;
+use std::str::FromStr;
+
+/// How the result of every game is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format: {}", s)),
+        }
+    }
+}
+
+const FIELDS: [&str; 12] = [
+    "config",
+    "seed",
+    "moves",
+    "biggest",
+    "score",
+    "score_per_move",
+    "elapsed_ms",
+    "ms_per_move",
+    "nodes",
+    "cache_hits",
+    "reused_hits",
+    "algorithm",
+];
+
+impl Format {
+    /// The line that goes before all the records, if the format has one
+    pub fn header(self) -> Option<String> {
+        match self {
+            Format::Csv => Some(FIELDS.join(",")),
+            Format::Text | Format::JsonLines => None,
+        }
+    }
+
+    /// A line with the result of a game. `finished` and `started` are the places of the game in
+    /// the order games finished and started.
+    pub fn record(self, result: &RunResult, finished: usize, started: usize) -> String {
+        let per_move = result.per_move().num_microseconds().unwrap() as f64 / 1000.0;
+        let elapsed = result.elapsed.num_milliseconds();
+        let values = [
+            result.config.to_string(),
+            result.seed.to_string(),
+            result.moves.to_string(),
+            result.biggest.to_string(),
+            result.score.to_string(),
+            format!("{:.3}", result.score_per_move()),
+            elapsed.to_string(),
+            format!("{:.3}", per_move),
+            result.nodes.to_string(),
+            result.cache_hits.to_string(),
+            result.reused_hits.to_string(),
+            result.algorithm.to_string(),
+        ];
+        match self {
+            Format::Text => format!(
+                "Result #{:>3} ({:>3}): Seed: {:>20}; Finished: {:>4} sec; Survived: {:>5} moves; {:>4.1} ms per move; Biggest tile: {:>5}; Score: {:>7} ({:>5.1} per move); Cache hits: {:>4.1}% ({:>4.1}% reused)",
+                finished,
+                started,
+                result.seed,
+                result.elapsed.num_seconds(),
+                result.moves,
+                per_move,
+                result.biggest,
+                result.score,
+                result.score_per_move(),
+                result.hit_rate() * 100.0,
+                result.reuse_rate() * 100.0,
+            ),
+            Format::JsonLines => {
+                let fields = FIELDS
+                    .iter()
+                    .zip(values.iter())
+                    .map(|(field, value)| {
+                        // Everything but the names is a number
+                        if *field == "config" || *field == "algorithm" {
+                            format!("\"{}\":\"{}\"", field, value)
+                        } else {
+                            format!("\"{}\":{}", field, value)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(","))
+            }
+            Format::Csv => values.join(","),
+        }
+    }
+}
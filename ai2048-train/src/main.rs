@@ -0,0 +1,112 @@
+use ai2048_lib::game_logic;
+use ai2048_lib::ntuple::{NTupleNetwork, TdTrainer};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Trains an n-tuple network by self-play with TD(λ). The network is saved after every batch of
+/// games in a format that `NTupleNetwork::load` can read.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ai2048-train")]
+struct Opt {
+    /// Number of games to play
+    #[structopt(short, long, default_value = "100000")]
+    episodes: u64,
+    /// Number of games between reports and saves
+    #[structopt(short, long, default_value = "1000", parse(try_from_str = parse_batch))]
+    batch: u64,
+    /// Step size of every update
+    #[structopt(short, long, default_value = "0.1")]
+    learning_rate: f32,
+    /// λ of TD(λ). 0 is plain TD(0)
+    #[structopt(long, default_value = "0")]
+    lambda: f32,
+    /// Seed of the random tiles. Random if omitted
+    #[structopt(short, long)]
+    seed: Option<u64>,
+    /// Cells of a tuple, separated by commas. Can be repeated. The default shape if omitted
+    #[structopt(short, long)]
+    tuple: Vec<String>,
+    /// Network to continue training. Overrides `--tuple`
+    #[structopt(short, long, parse(from_os_str))]
+    input: Option<PathBuf>,
+    /// File to write the network to
+    #[structopt(short, long, default_value = "network.bin", parse(from_os_str))]
+    output: PathBuf,
+}
+
+fn parse_batch(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("A batch must have at least one game".to_string()),
+        Ok(batch) => Ok(batch),
+        Err(e) => Err(format!("Invalid batch size: {}", e)),
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let mut network = match (&opt.input, opt.tuple.is_empty()) {
+        (Some(input), _) => NTupleNetwork::load(input).expect("Failed to read the network"),
+        (None, true) => NTupleNetwork::default(),
+        (None, false) => {
+            let tuples = opt
+                .tuple
+                .iter()
+                .map(|tuple| {
+                    tuple
+                        .split(',')
+                        .map(|cell| cell.trim().parse().ok())
+                        .collect()
+                })
+                .collect::<Option<Vec<Vec<usize>>>>()
+                .expect("Tuple cells must be numbers separated by commas");
+            let tuples = tuples
+                .iter()
+                .map(|tuple| tuple.as_slice())
+                .collect::<Vec<_>>();
+            NTupleNetwork::new(&tuples).expect("Tuple cells must be distinct and between 0 and 15")
+        }
+    };
+    let trainer = TdTrainer {
+        learning_rate: opt.learning_rate,
+        lambda: opt.lambda,
+        ..TdTrainer::default()
+    };
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    let mut rng = game_logic::seeded_rng(seed);
+
+    println!("SEED: {}", seed);
+    println!("LEARNING_RATE: {}", trainer.learning_rate);
+    println!("LAMBDA: {}", trainer.lambda);
+
+    let mut played = 0;
+    while played < opt.episodes {
+        let batch = opt.batch.min(opt.episodes - played);
+        let mut score = 0u64;
+        let mut moves = 0u64;
+        let mut reached_2048 = 0;
+        let mut biggest = 0;
+        for _ in 0..batch {
+            let episode = trainer.play_episode(&mut network, &mut rng);
+            score += u64::from(episode.score);
+            moves += u64::from(episode.moves);
+            biggest = biggest.max(episode.biggest_tile);
+            if episode.biggest_tile >= 2048 {
+                reached_2048 += 1;
+            }
+        }
+        played += batch;
+
+        println!(
+            "Games: {:>8}; Average score: {:>8.0}; Average moves: {:>6.0}; 2048 reached: {:>5.1}%; Biggest tile: {:>5}",
+            played,
+            score as f64 / batch as f64,
+            moves as f64 / batch as f64,
+            reached_2048 as f64 * 100.0 / batch as f64,
+            biggest,
+        );
+        network
+            .save(&opt.output)
+            .expect("Failed to write the network");
+    }
+}
@@ -0,0 +1,161 @@
+use ai2048_lib::game_logic::{self, GameEngine, Grid};
+use ai2048_lib::heuristic::{HeuristicWeights, RowHeuristic};
+use ai2048_lib::searcher::Searcher;
+use rayon::prelude::*;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Tunes the weights of the heuristic with coordinate descent. Every candidate is scored by
+/// playing the same batch of seeded games, and the best weights found so far are written to a
+/// file that `HeuristicWeights::load` can read.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ai2048-tune")]
+struct Opt {
+    /// Games played to score every candidate
+    #[structopt(short, long, default_value = "32", parse(try_from_str = parse_games))]
+    games: u64,
+    /// Cutoff probability of the search. Bigger is faster, but weaker
+    #[structopt(short, long, default_value = "0.01")]
+    min_probability: f32,
+    /// Seed of the first game of the batch. Random if omitted
+    #[structopt(short, long)]
+    seed: Option<u64>,
+    /// Weights to start from, five numbers separated by commas. Defaults if omitted
+    #[structopt(short, long)]
+    weights: Option<String>,
+    /// Maximum number of passes over all weights
+    #[structopt(short, long, default_value = "20")]
+    passes: usize,
+    /// Initial relative change of a weight. Halved after every pass without improvement
+    #[structopt(long, default_value = "0.5")]
+    step: f32,
+    /// Stop once the relative change falls below this
+    #[structopt(long, default_value = "0.01")]
+    min_step: f32,
+    /// Number of threads to play games on. All cores if omitted
+    #[structopt(short, long)]
+    threads: Option<usize>,
+    /// File to write the best weights to
+    #[structopt(short, long, default_value = "weights.txt", parse(from_os_str))]
+    output: PathBuf,
+}
+
+fn parse_games(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("At least one game is needed to score a candidate".to_string()),
+        Ok(games) => Ok(games),
+        Err(e) => Err(format!("Invalid number of games: {}", e)),
+    }
+}
+
+const WEIGHT_COUNT: usize = 5;
+
+fn main() {
+    let opt = Opt::from_args();
+
+    if let Some(threads) = opt.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Failed to set up the thread pool");
+    }
+
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    let mut best = match &opt.weights {
+        Some(weights) => HeuristicWeights::from_display(weights)
+            .expect("The weights must be five numbers separated by commas"),
+        None => HeuristicWeights::default(),
+    };
+
+    println!("MIN_PROBABILITY: {}", opt.min_probability);
+    println!("SEED: {}", seed);
+    println!("GAMES: {}", opt.games);
+
+    let mut best_fitness = evaluate(best, &opt, seed);
+    println!("Start: {} => {}", best, best_fitness);
+    save(best, &opt);
+
+    let mut step = opt.step;
+    for pass in 1..=opt.passes {
+        if step < opt.min_step {
+            break;
+        }
+
+        let mut improved = false;
+        for index in 0..WEIGHT_COUNT {
+            for &factor in &[1.0 + step, 1.0 / (1.0 + step)] {
+                let candidate = scale(best, index, factor);
+                let fitness = evaluate(candidate, &opt, seed);
+                println!(
+                    "Pass {:>2}, step {:.3}: {} => {}",
+                    pass, step, candidate, fitness
+                );
+                if fitness > best_fitness {
+                    best = candidate;
+                    best_fitness = fitness;
+                    improved = true;
+                    save(best, &opt);
+                    break;
+                }
+            }
+        }
+
+        if !improved {
+            step /= 2.0;
+        }
+    }
+
+    println!("Best: {} => {}", best, best_fitness);
+}
+
+fn save(weights: HeuristicWeights, opt: &Opt) {
+    weights
+        .save(&opt.output)
+        .expect("Failed to write the weights");
+}
+
+// Multiplies a single weight by `factor`. A weight that is zero stays zero.
+fn scale(weights: HeuristicWeights, index: usize, factor: f32) -> HeuristicWeights {
+    let mut weights = weights;
+    let weight = match index {
+        0 => &mut weights.not_lost,
+        1 => &mut weights.monotonicity,
+        2 => &mut weights.empty,
+        3 => &mut weights.adjacent,
+        _ => &mut weights.sum,
+    };
+    *weight *= factor;
+    weights
+}
+
+// The average score of a batch of games. Every candidate plays the same games, so the
+// difference between candidates isn't drowned in the luck of the spawns.
+fn evaluate(weights: HeuristicWeights, opt: &Opt, seed: u64) -> f64 {
+    let heuristic = RowHeuristic::with_weights(weights);
+    let total = (0..opt.games)
+        .into_par_iter()
+        .map(|game| play_one(seed.wrapping_add(game), &heuristic, opt.min_probability))
+        .sum::<u64>();
+    total as f64 / opt.games as f64
+}
+
+fn play_one(seed: u64, heuristic: &RowHeuristic, min_probability: f32) -> u64 {
+    let game_engine = GameEngine::new();
+    let mut rng = game_logic::seeded_rng(seed);
+    let mut grid = Grid::default()
+        .add_random_tile_with(&mut rng)
+        .add_random_tile_with(&mut rng);
+    // Games already keep all cores busy, and every candidate has to see the same games
+    let mut searcher = Searcher::with_heuristic(heuristic.clone()).with_deterministic(true);
+    let mut score = 0;
+    loop {
+        let result = searcher.search(grid, min_probability);
+        if let Some(mv) = result.best_move {
+            let outcome = game_engine.make_scored_move(grid, mv);
+            score += u64::from(outcome.score);
+            grid = outcome.grid.add_random_tile_with(&mut rng);
+        } else {
+            return score;
+        }
+    }
+}
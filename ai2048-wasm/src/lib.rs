@@ -1,9 +1,15 @@
 #![allow(clippy::needless_pass_by_value)]
 
-use ai2048_lib::game_logic;
-use ai2048_lib::searcher;
+use ai2048_lib::game_logic::{self, GridError, SpawnRule};
+use ai2048_lib::heuristic::{HeuristicWeights, RowHeuristic};
+use ai2048_lib::record::{GameRecord, Spawn, Turn};
+use ai2048_lib::searcher::{self, SearchControl, SearchProgress, SearchResult, Searcher};
 use cfg_if::cfg_if;
 use console_error_panic_hook::set_once as set_panic_hook;
+use js_sys::{Array, Atomics, Float32Array, Int32Array, Object, Reflect};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 // use web_sys::console;
 
@@ -17,11 +23,40 @@ cfg_if! {
     }
 }
 
+thread_local! {
+    static HEURISTIC: RefCell<RowHeuristic> = RefCell::new(RowHeuristic::new());
+}
+
 #[wasm_bindgen]
 pub fn init() {
     set_panic_hook();
 }
 
+#[wasm_bindgen]
+pub fn set_heuristic_weights(
+    not_lost: f32,
+    monotonicity: f32,
+    empty: f32,
+    adjacent: f32,
+    sum: f32,
+) -> Result<(), JsValue> {
+    let weights = [not_lost, monotonicity, empty, adjacent, sum];
+    if let Some(weight) = weights.iter().find(|weight| !weight.is_finite()) {
+        return Err(
+            js_sys::Error::new(&format!("Invalid weights: {} isn't finite", weight)).into(),
+        );
+    }
+    let weights = HeuristicWeights {
+        not_lost,
+        monotonicity,
+        empty,
+        adjacent,
+        sum,
+    };
+    HEURISTIC.with(|heuristic| *heuristic.borrow_mut() = RowHeuristic::with_weights(weights));
+    Ok(())
+}
+
 #[wasm_bindgen]
 #[repr(u8)]
 #[derive(Debug)]
@@ -33,6 +68,18 @@ pub enum Move {
     None = 4,
 }
 
+impl From<Move> for Option<game_logic::Move> {
+    fn from(mv: Move) -> Option<game_logic::Move> {
+        match mv {
+            Move::Up => Some(game_logic::Move::Up),
+            Move::Down => Some(game_logic::Move::Down),
+            Move::Left => Some(game_logic::Move::Left),
+            Move::Right => Some(game_logic::Move::Right),
+            Move::None => None,
+        }
+    }
+}
+
 impl From<Option<game_logic::Move>> for Move {
     fn from(mv: Option<game_logic::Move>) -> Move {
         match mv {
@@ -45,18 +92,553 @@ impl From<Option<game_logic::Move>> for Move {
     }
 }
 
+/// Finds the best move for `grid`, 16 tiles column by column. Like every function that takes a
+/// grid, throws an `Error` if there aren't 16 tiles or a tile isn't 0 or a power of 2.
+#[wasm_bindgen]
+pub fn evaluate_position(grid: Box<[u32]>, min_prob: f32) -> Result<Move, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_with_heuristic(grid, min_prob, SpawnRule::default(), &*heuristic.borrow())
+    });
+    Ok(result.best_move.into())
+}
+
+/// Same as `evaluate_position`, but searches for no longer than `budget_ms` milliseconds. A
+/// negative budget searches as little as possible, and an infinite one never runs out. Throws an
+/// `Error` if `budget_ms` is NaN.
+#[wasm_bindgen]
+pub fn evaluate_position_timed(grid: Box<[u32]>, budget_ms: f64) -> Result<Move, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let budget = budget_from_ms(budget_ms)?;
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_timed_with_heuristic(
+            grid,
+            budget,
+            SpawnRule::default(),
+            &*heuristic.borrow(),
+        )
+    });
+    Ok(result.best_move.into())
+}
+
+/// Same as `evaluate_position`, but returns everything the search found out, as an object:
+///
+/// - `bestMove`: the best move, as a `Move`
+/// - `evaluations`: the evaluations of Up, Right, Down and Left, with NaN for impossible moves
+/// - `depth`: the search depth
+/// - `minProbability`: the cutoff probability
+/// - `stats`: `{ nodes, cacheSize, cacheHits, reusedHits, symmetricHits, evals, average }`, the
+///   same statistics the command-line runner shows
+///
+/// It's a plain object, so it can be posted from a worker.
+#[wasm_bindgen]
+pub fn analyze_position(grid: Box<[u32]>, min_prob: f32) -> Result<JsValue, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_with_heuristic(grid, min_prob, SpawnRule::default(), &*heuristic.borrow())
+    });
+    Ok(result_object(&result).into())
+}
+
+/// Same as `analyze_position`, but searches for no longer than `budget_ms`, like
+/// `evaluate_position_timed`.
+#[wasm_bindgen]
+pub fn analyze_position_timed(grid: Box<[u32]>, budget_ms: f64) -> Result<JsValue, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let budget = budget_from_ms(budget_ms)?;
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_timed_with_heuristic(
+            grid,
+            budget,
+            SpawnRule::default(),
+            &*heuristic.borrow(),
+        )
+    });
+    Ok(result_object(&result).into())
+}
+
+/// Same as `evaluate_position`, but returns an object that explains the move:
+///
+/// - `result`: the search, as `analyze_position` returns it
+/// - `line`: the line of play the search expects, as a list of `{ move, evaluation, spawn }`,
+///   where `spawn` is the tile expected after the move as `{ cell, value, probability }`, or
+///   `null` at the search horizon
+/// - `leaf`: the grid at the end of the line
+/// - `features`: what the features of the heuristic contribute to its evaluation of `leaf`, as
+///   `{ notLost, monotonicity, empty, adjacency, sum, total }`
+/// - `gameOverProbability`: the chance of game over within the search horizon
+///
+/// Grids and cells are laid out the same way as the grid passed in. It's a plain object, so it
+/// can be posted from a worker.
+#[wasm_bindgen]
+pub fn explain_position(grid: Box<[u32]>, min_prob: f32) -> Result<JsValue, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let explanation = HEURISTIC.with(|heuristic| {
+        searcher::explain_with_heuristic(grid, min_prob, SpawnRule::default(), &*heuristic.borrow())
+    });
+
+    let line = Array::new();
+    for ply in &explanation.principal_variation {
+        let spawn = match ply.spawn {
+            Some((spawn, probability)) => {
+                let object = Object::new();
+                set(&object, "cell", cell_index(spawn.index) as u32);
+                set(&object, "value", spawn.value);
+                set(&object, "probability", probability);
+                object.into()
+            }
+            None => JsValue::NULL,
+        };
+        let object = Object::new();
+        set(&object, "move", Move::from(Some(ply.mv)) as u8);
+        set(&object, "evaluation", ply.eval);
+        set(&object, "spawn", spawn);
+        line.push(&object);
+    }
+
+    let features = match explanation.features {
+        Some(features) => {
+            let object = Object::new();
+            set(&object, "notLost", features.not_lost);
+            set(&object, "monotonicity", features.monotonicity);
+            set(&object, "empty", features.empty);
+            set(&object, "adjacency", features.adjacent);
+            set(&object, "sum", features.sum);
+            set(&object, "total", features.total());
+            object.into()
+        }
+        None => JsValue::NULL,
+    };
+
+    let object = Object::new();
+    set(&object, "result", result_object(&explanation.result));
+    set(&object, "line", line);
+    set(
+        &object,
+        "leaf",
+        js_sys::Uint32Array::from(&untransform_grid(explanation.leaf)[..]),
+    );
+    set(&object, "features", features);
+    set(
+        &object,
+        "gameOverProbability",
+        explanation.game_over_probability,
+    );
+    Ok(object.into())
+}
+
+/// Makes `mv` on `grid` and returns where every tile went, so that the move can be animated:
+///
+/// - `grid`: the grid after the move, before a tile spawns
+/// - `score`: the points gained by the move
+/// - `tiles`: every tile of `grid` as `{ from, to, value, merged }`, where `from` and `to` are
+///   cells, `value` is the value of the tile before the move, and `merged` says whether it merged
+///   with another tile on `to`
+///
+/// Grids and cells are laid out the same way as the grid passed in. Throws an `Error` if `mv` is
+/// `Move.None`.
+#[wasm_bindgen]
+pub fn trace_move(grid: Box<[u32]>, mv: Move) -> Result<JsValue, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let mv = Option::<game_logic::Move>::from(mv)
+        .ok_or_else(|| JsValue::from(js_sys::Error::new("There's no move to trace")))?;
+    let traced = game_logic::GameEngine::new().make_traced_move(grid, mv);
+
+    let tiles = Array::new();
+    for tile in &traced.tiles {
+        let object = Object::new();
+        set(&object, "from", cell_index(tile.from) as u32);
+        set(&object, "to", cell_index(tile.to) as u32);
+        set(&object, "value", tile.value);
+        set(&object, "merged", tile.merged);
+        tiles.push(&object);
+    }
+
+    let object = Object::new();
+    set(
+        &object,
+        "grid",
+        js_sys::Uint32Array::from(&untransform_grid(traced.outcome.grid)[..]),
+    );
+    set(&object, "score", traced.outcome.score);
+    set(&object, "tiles", tiles);
+    Ok(object.into())
+}
+
+/// Lets the page stop a search that is running in a worker. `flag` is an `Int32Array` the page
+/// shares with the worker, over a `SharedArrayBuffer`, and the token stays uncancelled as long as
+/// `flag[0]` holds `epoch`. The page cancels every search it started so far by bumping `flag[0]`
+/// with `Atomics.add`, and the search notices within a few thousand nodes, as it checks the flag
+/// with `Atomics.load` while it runs. Without cross-origin isolation there's no
+/// `SharedArrayBuffer`, and an `Int32Array` of its own only lets `progress` cancel the search.
+///
+/// Throws an `Error` if `flag` is empty.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct CancellationToken {
+    flag: Int32Array,
+    epoch: i32,
+}
+
+// The search wants a check it can share between threads, but wasm only ever has the one
+unsafe impl Sync for CancellationToken {}
+
+#[wasm_bindgen]
+impl CancellationToken {
+    #[wasm_bindgen(constructor)]
+    pub fn new(flag: Int32Array, epoch: i32) -> Result<CancellationToken, JsValue> {
+        if flag.length() == 0 {
+            return Err(js_sys::Error::new("The cancellation flag needs one element").into());
+        }
+        Ok(CancellationToken { flag, epoch })
+    }
+
+    pub fn cancel(&self) {
+        let _ = Atomics::compare_exchange(&self.flag, 0, self.epoch, self.epoch.wrapping_add(1));
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        Atomics::load(&self.flag, 0) != Ok(self.epoch)
+    }
+}
+
+/// A whole game played inside wasm: the grid, the tiles that spawn on it, the score and an AI
+/// to play it. Lets JS run a game in a loop without a round trip for every move.
 #[wasm_bindgen]
-pub fn evaluate_position(grid: Box<[u32]>, min_prob: f32) -> Move {
-    let grid = transform_grid(&grid);
-    let result = searcher::search(grid, min_prob);
-    result.best_move.into()
+#[derive(Debug)]
+pub struct GameSession {
+    game_engine: game_logic::GameEngine,
+    grid: game_logic::Grid,
+    rng: game_logic::GameRng,
+    searcher: Searcher,
+    score: u32,
+    moves: u32,
+    record: GameRecord,
 }
 
-fn transform_grid(grid: &[u32]) -> game_logic::Grid {
-    let row0 = [grid[0], grid[4], grid[8], grid[12]];
-    let row1 = [grid[1], grid[5], grid[9], grid[13]];
-    let row2 = [grid[2], grid[6], grid[10], grid[14]];
-    let row3 = [grid[3], grid[7], grid[11], grid[15]];
-    let grid = [row0, row1, row2, row3];
-    game_logic::Grid::from_human(grid).unwrap()
+#[wasm_bindgen]
+impl GameSession {
+    /// Starts a game with two random tiles. The same `seed` spawns the same tiles. The AI uses
+    /// the heuristic weights set when the game starts.
+    #[wasm_bindgen(constructor)]
+    pub fn new(seed: u32) -> GameSession {
+        let game_engine = game_logic::GameEngine::new();
+        let mut rng = game_logic::seeded_rng(u64::from(seed));
+        let grid = game_engine.add_random_tile(game_logic::Grid::default(), &mut rng);
+        let grid = game_engine.add_random_tile(grid, &mut rng);
+        let heuristic = HEURISTIC.with(|heuristic| heuristic.borrow().clone());
+        GameSession {
+            game_engine,
+            grid,
+            rng,
+            searcher: Searcher::with_heuristic(heuristic),
+            score: 0,
+            moves: 0,
+            record: GameRecord::new(grid),
+        }
+    }
+
+    /// The grid, laid out the same way as the grids the other functions take
+    pub fn grid(&self) -> Box<[u32]> {
+        Box::new(untransform_grid(self.grid))
+    }
+
+    /// Points gained so far
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    /// Moves made so far
+    pub fn moves(&self) -> u32 {
+        self.moves
+    }
+
+    /// The biggest tile on the grid
+    pub fn biggest_tile(&self) -> u32 {
+        self.grid.biggest_tile()
+    }
+
+    /// The record of the game so far, in the format `GameRecord::save` writes
+    pub fn record(&self) -> String {
+        record_text(&self.record)
+    }
+
+    /// Whether there are no moves left
+    pub fn is_over(&self) -> bool {
+        self.game_engine.player_moves(self.grid).next().is_none()
+    }
+
+    /// Makes `mv` and spawns a tile. Returns `false`, and changes nothing, if the move doesn't
+    /// change the grid.
+    pub fn play(&mut self, mv: Move) -> bool {
+        let mv = match Option::<game_logic::Move>::from(mv) {
+            Some(mv) => mv,
+            None => return false,
+        };
+        let outcome = self.game_engine.make_scored_move(self.grid, mv);
+        if outcome.grid == self.grid {
+            return false;
+        }
+        self.grid = self
+            .game_engine
+            .add_random_tile(outcome.grid, &mut self.rng);
+        self.record
+            .push(mv, outcome.grid, self.grid)
+            .expect("Exactly one tile spawns after every move");
+        self.score += outcome.score;
+        self.moves += 1;
+        true
+    }
+
+    /// Lets the AI search with `min_prob` and make its move. Returns the move, or `Move.None`
+    /// once the game is over.
+    pub fn step(&mut self, min_prob: f32) -> Move {
+        let result = self.searcher.search(self.grid, min_prob);
+        if let Some(mv) = result.best_move {
+            self.play(Some(mv).into());
+        }
+        result.best_move.into()
+    }
+
+    /// Lets the AI play up to `max_moves` moves, or until the game is over. Returns the number
+    /// of moves made.
+    pub fn auto_play(&mut self, min_prob: f32, max_moves: u32) -> u32 {
+        let mut moves = 0;
+        while moves < max_moves {
+            if let Move::None = self.step(min_prob) {
+                break;
+            }
+            moves += 1;
+        }
+        moves
+    }
+}
+
+// The searcher wants a callback it can share between threads, but wasm only ever has the one
+struct JsProgress(js_sys::Function);
+
+unsafe impl Sync for JsProgress {}
+
+impl JsProgress {
+    // Calls the function with the node count, the depth and the evaluations of Up, Right, Down
+    // and Left, with NaN for moves that aren't evaluated yet. Exceptions are ignored.
+    fn report(&self, progress: &SearchProgress) {
+        let _ = self.0.call3(
+            &JsValue::NULL,
+            &progress.nodes.into(),
+            &progress.depth.into(),
+            &evaluation_array(&progress.move_evaluations),
+        );
+    }
+}
+
+/// Same as `evaluate_position`, but returns `undefined` once `token` is cancelled and calls
+/// `progress`, if any, as the search goes on.
+#[wasm_bindgen]
+pub fn evaluate_position_cancellable(
+    grid: Box<[u32]>,
+    min_prob: f32,
+    token: &CancellationToken,
+    progress: Option<js_sys::Function>,
+) -> Result<Option<Move>, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let progress = progress.map(JsProgress);
+    let report = |p: &SearchProgress| {
+        if let Some(progress) = &progress {
+            progress.report(p);
+        }
+    };
+    let cancel_check = || token.is_cancelled();
+    let control = SearchControl::new()
+        .with_cancel_check(&cancel_check)
+        .with_progress(&report);
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_controlled(
+            grid,
+            min_prob,
+            SpawnRule::default(),
+            &*heuristic.borrow(),
+            &control,
+        )
+    });
+    Ok(result.map(|result| result.best_move.into()))
+}
+
+/// Same as `evaluate_position_timed`, but returns `undefined` once `token` is cancelled and
+/// calls `progress`, if any, as the search goes on.
+#[wasm_bindgen]
+pub fn evaluate_position_timed_cancellable(
+    grid: Box<[u32]>,
+    budget_ms: f64,
+    token: &CancellationToken,
+    progress: Option<js_sys::Function>,
+) -> Result<Option<Move>, JsValue> {
+    let grid = transform_grid(&grid)?;
+    let budget = budget_from_ms(budget_ms)?;
+    let progress = progress.map(JsProgress);
+    let report = |p: &SearchProgress| {
+        if let Some(progress) = &progress {
+            progress.report(p);
+        }
+    };
+    let cancel_check = || token.is_cancelled();
+    let control = SearchControl::new()
+        .with_cancel_check(&cancel_check)
+        .with_progress(&report);
+    let result = HEURISTIC.with(|heuristic| {
+        searcher::search_timed_controlled(
+            grid,
+            budget,
+            SpawnRule::default(),
+            &*heuristic.borrow(),
+            &control,
+        )
+    });
+    if token.is_cancelled() {
+        return Ok(None);
+    }
+    Ok(result.map(|result| result.best_move.into()))
+}
+
+/// Writes the record of a game played outside wasm, in the format `GameRecord::save` writes, so
+/// that it can be replayed. `start` is the grid the game started from, and `turns` has three
+/// numbers for every turn: the `Move`, the cell the tile spawned on, laid out like the grids, and
+/// the value of the tile. Throws an `Error` if the game doesn't replay.
+#[wasm_bindgen]
+pub fn write_record(start: Box<[u32]>, turns: &[u32]) -> Result<String, JsValue> {
+    let invalid = |message: String| JsValue::from(js_sys::Error::new(&message));
+    let chunks = turns.chunks_exact(3);
+    if !chunks.remainder().is_empty() {
+        return Err(invalid(format!(
+            "Invalid record: {} numbers don't make whole turns",
+            turns.len()
+        )));
+    }
+    let mut record = GameRecord::new(transform_grid(&start)?);
+    for (turn, numbers) in chunks.enumerate() {
+        let mv = match numbers[0] {
+            0 => game_logic::Move::Up,
+            1 => game_logic::Move::Right,
+            2 => game_logic::Move::Down,
+            3 => game_logic::Move::Left,
+            _ => {
+                return Err(invalid(format!(
+                    "Invalid record: turn {}: {} is not a move",
+                    turn, numbers[0]
+                )))
+            }
+        };
+        if numbers[1] >= 16 {
+            return Err(invalid(format!(
+                "Invalid record: turn {}: {} is not a cell",
+                turn, numbers[1]
+            )));
+        }
+        let spawn = Spawn {
+            index: cell_index(numbers[1] as usize),
+            value: numbers[2],
+        };
+        record.turns.push(Turn { mv, spawn });
+    }
+    record
+        .replay(game_logic::GameEngine::new())
+        .map_err(|error| invalid(format!("Invalid record: {}", error)))?;
+    Ok(record_text(&record))
+}
+
+// Budgets too big for a `Duration` never run out, just like the biggest `Duration`
+fn budget_from_ms(budget_ms: f64) -> Result<Duration, JsValue> {
+    if budget_ms.is_nan() {
+        return Err(js_sys::Error::new("Invalid budget: NaN milliseconds").into());
+    }
+    let secs = budget_ms.max(0.0) / 1000.0;
+    if secs < u64::MAX as f64 {
+        Ok(Duration::from_secs_f64(secs))
+    } else {
+        Ok(Duration::MAX)
+    }
+}
+
+fn record_text(record: &GameRecord) -> String {
+    let mut text = Vec::new();
+    record
+        .write_to(&mut text)
+        .expect("Failed to write a record to memory");
+    String::from_utf8(text).expect("Records are text")
+}
+
+fn result_object(result: &SearchResult) -> Object {
+    let stats = Object::new();
+    set(&stats, "nodes", result.stats.nodes);
+    set(&stats, "cacheSize", result.stats.cache_size);
+    set(&stats, "cacheHits", result.stats.cache_hits);
+    set(&stats, "reusedHits", result.stats.reused_hits);
+    set(&stats, "symmetricHits", result.stats.symmetric_hits);
+    set(&stats, "evals", result.stats.evals);
+    set(&stats, "average", result.stats.average);
+
+    let object = Object::new();
+    set(&object, "bestMove", Move::from(result.best_move) as u8);
+    set(
+        &object,
+        "evaluations",
+        evaluation_array(&result.move_evaluations),
+    );
+    set(&object, "depth", result.depth);
+    set(&object, "minProbability", result.min_probability);
+    set(&object, "stats", stats);
+    object
+}
+
+// Evaluations of Up, Right, Down and Left, with NaN for the moves that have none
+fn evaluation_array(move_evaluations: &HashMap<game_logic::Move, f32>) -> Float32Array {
+    let mut evaluations = [f32::NAN; 4];
+    for (&mv, &eval) in move_evaluations {
+        evaluations[Move::from(Some(mv)) as usize] = eval;
+    }
+    Float32Array::from(&evaluations[..])
+}
+
+fn set(object: &Object, key: &str, value: impl Into<JsValue>) {
+    Reflect::set(object, &key.into(), &value.into()).expect("Failed to set a property");
+}
+
+// The index of a cell counted row by row, in the layout of the grids JS passes in
+fn cell_index(index: usize) -> usize {
+    index % 4 * 4 + index / 4
+}
+
+fn untransform_grid(grid: game_logic::Grid) -> [u32; 16] {
+    let mut cells = [0; 16];
+    for (index, &tile) in grid.unpack_human().iter().flatten().enumerate() {
+        cells[cell_index(index)] = tile;
+    }
+    cells
+}
+
+// Grids come from JS column by column. Throws a JS `Error` naming the wrong cell by its index
+// in the array JS passed in, instead of panicking the worker.
+fn transform_grid(grid: &[u32]) -> Result<game_logic::Grid, JsValue> {
+    if grid.len() != 16 {
+        let error = GridError::WrongTileCount {
+            expected: 16,
+            count: grid.len(),
+        };
+        return Err(js_sys::Error::new(&format!("Invalid grid: {}", error)).into());
+    }
+    let mut tiles = [[0; 4]; 4];
+    for (index, tile) in tiles.iter_mut().flatten().enumerate() {
+        *tile = grid[cell_index(index)];
+    }
+    game_logic::Grid::from_human(tiles).map_err(|error| {
+        let message = match error {
+            GridError::InvalidTile { row, column, tile } => format!(
+                "Invalid grid: {} at index {} is not 0 or a power of 2 up to 262144",
+                tile,
+                cell_index(row * 4 + column)
+            ),
+            _ => format!("Invalid grid: {}", error),
+        };
+        js_sys::Error::new(&message).into()
+    })
 }
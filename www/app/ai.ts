@@ -1,31 +1,67 @@
 import PromiseWorker from "promise-worker";
 import { Direction } from "./direction";
 
+// Where a tile went during a move, with cells counted like `Grid.forAi`
+export interface TileMotion {
+  from: number;
+  to: number;
+  // The value of the tile before the move
+  value: number;
+  // Whether the tile merged with another one on `to`. Both tiles of a merge are listed.
+  merged: boolean;
+}
+
+// A move made by the AI's engine, before a new tile spawns
+export interface TracedMove {
+  grid: Uint32Array;
+  score: number;
+  tiles: TileMotion[];
+}
+
 interface MessageForAi {
   grid: Uint32Array;
-  minProb: number;
+  minProb?: number;
+  budgetMs?: number;
+  epoch?: number;
 }
 
+// Milliseconds the AI may think about a move. The search picks its own depth and cutoff
+// probability to fit, so every strength has a bounded latency.
 const StrengthMap: { [index: number]: number } = {
-  1: 0.02,
-  2: 0.01,
-  3: 0.005,
-  4: 0.003,
-  5: 0.002,
-  6: 0.001,
-  7: 0.0005,
-  8: 0.0003,
-  9: 0.0002,
-  10: 0.0001
+  1: 5,
+  2: 10,
+  3: 20,
+  4: 30,
+  5: 50,
+  6: 75,
+  7: 100,
+  8: 150,
+  9: 250,
+  10: 400
 };
 const MinStrength = 1;
 const MaxStrength = 10;
 
 export default class Ai {
   private readonly worker: PromiseWorker;
+  // `cancel` bumps the epoch in here. When the page is cross-origin isolated, the worker shares
+  // it and stops searching as soon as it changes; otherwise the search runs to the end and its
+  // move is dropped.
+  private readonly cancelFlag: Int32Array;
+  // Whether the worker has `cancelFlag` too. Otherwise it must not get an epoch: its own flag
+  // never moves, so a search sent after the first `cancel` would be cancelled before it starts.
+  private readonly sharedFlag: boolean;
   private strength = 8;
   public constructor() {
     this.worker = new PromiseWorker(new Worker("./worker.js"));
+    if (typeof SharedArrayBuffer !== "undefined") {
+      this.cancelFlag = new Int32Array(new SharedArrayBuffer(4));
+      this.worker.postMessage({ cancelFlag: this.cancelFlag });
+      this.sharedFlag = true;
+    } else {
+      this.cancelFlag = new Int32Array(1);
+      this.sharedFlag = false;
+    }
   }
   public getStrength(): number {
     return this.strength;
@@ -48,13 +84,49 @@ export default class Ai {
     }
     return this.strength;
   }
-  public async chooseDirection(grid: Uint32Array): Promise<Direction> {
-    const minProb = StrengthMap[this.strength];
+  // Resolves to undefined if `cancel` is called before the AI has chosen
+  public async chooseDirection(
+    grid: Uint32Array
+  ): Promise<Direction | undefined> {
+    const budgetMs = StrengthMap[this.strength];
+    const epoch = Atomics.load(this.cancelFlag, 0);
     const message: MessageForAi = {
       grid: grid,
-      minProb: minProb
+      budgetMs: budgetMs
     };
+    if (this.sharedFlag) {
+      message.epoch = epoch;
+    }
     const reply = await this.worker.postMessage(message);
+    if (Atomics.load(this.cancelFlag, 0) !== epoch) {
+      return undefined;
+    }
     return reply;
   }
+  // Makes a move the way the AI's engine does, and tells where every tile went
+  public async traceMove(
+    grid: Uint32Array,
+    direction: Direction
+  ): Promise<TracedMove> {
+    return this.worker.postMessage({
+      traceMove: direction,
+      grid: grid
+    });
+  }
+  // The record of a game in the format of `GameRecord::save`. `turns` has the direction, the
+  // cell of the new tile and its value for every turn, with cells counted like `Grid.forAi`.
+  public async writeRecord(
+    start: Uint32Array,
+    turns: Uint32Array
+  ): Promise<string> {
+    return this.worker.postMessage({
+      writeRecord: true,
+      start: start,
+      turns: turns
+    });
+  }
+  // Stops every search started so far
+  public cancel(): void {
+    Atomics.add(this.cancelFlag, 0, 1);
+  }
 }
@@ -22,6 +22,11 @@ const DirectionMap = new Map<Direction, Vector>([
   [Direction.Left, { x: -1, y: 0 }]
 ]);
 
+// The position of a cell, counted like `Grid.forAi`
+function cellPosition(cell: number): Position {
+  return { x: Math.floor(cell / Size), y: cell % Size };
+}
+
 export default class GameManager {
   private readonly storageManager: StorageManager;
   private readonly actuator: Actuator;
@@ -32,7 +37,17 @@ export default class GameManager {
   private won = false;
   private score = 0;
   private aiIsRunning = false;
+  // Bumped when the AI is paused or the game restarts, so that moves chosen before then are
+  // dropped
+  private aiRun = 0;
+  // Moves wait for the ones before them, so that every move starts from the grid the one
+  // before left
+  private moving: Promise<void> = Promise.resolve();
   private throttleAi = true;
+  // The record of the game: the grid it started from, and the direction, the cell of the new
+  // tile and its value for every turn, with cells counted like `Grid.forAi`
+  private recordStart: number[] = [];
+  private recordTurns: number[] = [];
 
   public constructor(
     storageManager: StorageManager,
@@ -51,61 +66,24 @@ export default class GameManager {
     } else {
       this.clearState();
       this.addStartTiles();
+      this.recordStart = Array.from(this.grid.forAi());
     }
     this.actuate();
   }
-  // Move tiles on the grid in the specified direction
-  public move(direction: Direction): void {
-    if (this.isGameTerminated()) return; // Don't do anything if the game's over
-    const vector = this.getVector(direction);
-    const traversals = this.buildTraversals(vector);
-    let moved = false;
-    // Save the current tile positions and remove merger information
-    this.prepareTiles();
-    // Traverse the grid in the right direction and move tiles
-    for (const x of traversals.x) {
-      for (const y of traversals.y) {
-        const position: Position = { x: x, y: y };
-        const tile = this.grid.tileAtPosition(position);
-        if (tile) {
-          const { farthest, next } = this.findFarthestPosition(
-            position,
-            vector
-          );
-          const nextTile = this.grid.tileAtPosition(next);
-          // Only one merger per row traversal?
-          if (
-            nextTile &&
-            nextTile.value === tile.value &&
-            !nextTile.mergedFrom
-          ) {
-            const merged = new Tile(next, tile.value * 2);
-            merged.mergedFrom = [tile, nextTile];
-            this.grid.insertTile(merged);
-            this.grid.removeTileAtPosition(tile);
-            // Converge the two tiles' positions
-            tile.updatePosition(next);
-            // Update the score
-            this.score += merged.value;
-            // The mythical 65536 tile
-            if (merged.value === 65536) this.won = true;
-          } else {
-            this.moveTile(tile, farthest);
-          }
-          if (!this.positionsEqual(position, tile)) {
-            moved = true; // The tile moved from its original position!
-          }
-        }
-      }
-    }
-    if (moved) {
-      this.addRandomTile();
-      if (!this.movesAvailable()) {
-        this.over = true; // Game over!
-        this.aiIsRunning = false;
-      }
-      this.actuate();
-    }
+  // Moves tiles on the grid in the specified direction, once the moves before it are done
+  public move(direction: Direction): Promise<void> {
+    const move = this.moving.then(() => this.makeMove(direction));
+    // A move that failed must not hold up the ones after it
+    this.moving = move.catch(() => undefined);
+    return move;
+  }
+  // Lets the browser save the record of the game so far, which `GameRecord::load` can read
+  public async saveRecord(): Promise<void> {
+    const text = await this.ai.writeRecord(
+      new Uint32Array(this.recordStart),
+      new Uint32Array(this.recordTurns)
+    );
+    this.actuator.download("2048-record.txt", text);
   }
   public plus(): void {
     const strength = this.ai.increaseStrength();
@@ -119,6 +97,7 @@ export default class GameManager {
   }
   // Restart the game
   public restart(): void {
+    this.stopAi();
     this.storageManager.clearGameState();
     this.actuator.continueGame(); // Clear the game won/lost message
     this.setup();
@@ -132,13 +111,59 @@ export default class GameManager {
     this.aiIsRunning = !this.aiIsRunning;
     this.actuator.updateRunButton(this.aiIsRunning);
     if (this.aiIsRunning) {
-      this.ai.chooseDirection(this.grid.forAi()).then(d => this.move(d));
+      this.playAiMove(false);
+    } else {
+      this.stopAi();
     }
   }
   public toggleThrottle(): void {
     this.throttleAi = !this.throttleAi;
     this.actuator.updateThrottleButton(this.throttleAi);
   }
+  // Lets the AI's engine make the move, so that the page plays by the very same rules, and
+  // animates the tiles from where the engine says they went
+  private async makeMove(direction: Direction): Promise<void> {
+    if (this.isGameTerminated()) return; // Don't do anything if the game's over
+    const grid = this.grid;
+    const traced = await this.ai.traceMove(grid.forAi(), direction);
+    // The game restarted while the move was traced
+    if (grid !== this.grid || this.isGameTerminated()) return;
+    if (traced.tiles.every(motion => motion.from === motion.to)) return;
+    // Save the current tile positions and remove merger information
+    this.prepareTiles();
+    const tiles = traced.tiles.map(
+      motion => grid.tileAtPosition(cellPosition(motion.from))!
+    );
+    for (const tile of tiles) {
+      grid.removeTileAtPosition(tile);
+    }
+    // Both tiles of a merge end up on the same cell
+    const merges = new Map<number, Tile[]>();
+    traced.tiles.forEach((motion, index) => {
+      const tile = tiles[index];
+      tile.updatePosition(cellPosition(motion.to));
+      if (motion.merged) {
+        merges.set(motion.to, (merges.get(motion.to) || []).concat(tile));
+      } else {
+        grid.insertTile(tile);
+      }
+    });
+    for (const [cell, mergedFrom] of merges) {
+      const merged = new Tile(cellPosition(cell), mergedFrom[0].value * 2);
+      merged.mergedFrom = mergedFrom;
+      grid.insertTile(merged);
+      // The mythical 65536 tile
+      if (merged.value === 65536) this.won = true;
+    }
+    this.score += traced.score;
+    const tile = this.addRandomTile()!;
+    this.recordTurns.push(direction, tile.x * Size + tile.y, tile.value);
+    if (!this.movesAvailable()) {
+      this.over = true; // Game over!
+      this.aiIsRunning = false;
+    }
+    this.actuate();
+  }
   private loadState(previousState: GameState) {
     this.grid = new Grid(previousState.grid);
     this.score = previousState.score;
@@ -146,6 +171,10 @@ export default class GameManager {
     this.won = previousState.won;
     this.keepPlaying = previousState.keepPlaying;
     this.ai.setStrength(previousState.aiStrength);
+    // Games saved without a record are recorded from where they were left off
+    this.recordStart =
+      previousState.recordStart || Array.from(this.grid.forAi());
+    this.recordTurns = previousState.recordTurns || [];
   }
   private clearState(): void {
     this.grid = new Grid();
@@ -154,6 +183,8 @@ export default class GameManager {
     this.won = false;
     this.keepPlaying = false;
     this.aiIsRunning = false;
+    this.recordStart = [];
+    this.recordTurns = [];
   }
   // Sends the updated grid to the actuator
   private async actuate(): Promise<void> {
@@ -177,14 +208,26 @@ export default class GameManager {
       throttleIsOn: () => this.throttleAi
     });
     if (this.aiIsRunning) {
-      const to = timeout(100);
-      const direction = await this.ai.chooseDirection(this.grid.forAi());
-      if (this.throttleAi) {
-        await to; // make sure moves are at least 100 milliseconds
-      }
+      await this.playAiMove(this.throttleAi);
+    }
+  }
+  // Lets the AI choose a move and makes it, unless the AI is paused or the game restarts first
+  private async playAiMove(throttle: boolean): Promise<void> {
+    const run = this.aiRun;
+    const to = timeout(100);
+    const direction = await this.ai.chooseDirection(this.grid.forAi());
+    if (throttle) {
+      await to; // make sure moves are at least 100 milliseconds
+    }
+    if (direction !== undefined && run === this.aiRun) {
       this.move(direction);
     }
   }
+  // Stops the search for the AI's next move, if any
+  private stopAi(): void {
+    this.aiRun++;
+    this.ai.cancel();
+  }
   // Return true if the game is lost, or has won and the user hasn't kept playing
   private isGameTerminated(): boolean {
     return this.over || (this.won && !this.keepPlaying);
@@ -195,13 +238,15 @@ export default class GameManager {
       this.addRandomTile();
     }
   }
-  // Adds a tile in a random position
-  private addRandomTile(): void {
+  // Adds a tile in a random position and returns it, if there's room for one
+  private addRandomTile(): Tile | null {
     if (this.grid.tilesAvailable()) {
       const value = Math.random() < 0.9 ? 2 : 4;
       const tile = new Tile(this.grid.randomAvailablePosition()!, value);
       this.grid.insertTile(tile);
+      return tile;
     }
+    return null;
   }
   // Represent the current game as an object
   private serialize(): GameState {
@@ -211,7 +256,9 @@ export default class GameManager {
       over: this.over,
       won: this.won,
       keepPlaying: this.keepPlaying,
-      aiStrength: this.ai.getStrength()
+      aiStrength: this.ai.getStrength(),
+      recordStart: this.recordStart,
+      recordTurns: this.recordTurns
     };
   }
   // Save all tile positions and remove merger info
@@ -223,47 +270,10 @@ export default class GameManager {
       }
     });
   }
-  // Move a tile and its representation
-  private moveTile(tile: Tile, position: Position): void {
-    const tiles: any = this.grid.tiles;
-    tiles[tile.x][tile.y] = null;
-    tiles[position.x][position.y] = tile;
-    tile.updatePosition(position);
-  }
   // Get the vector representing the chosen direction
   private getVector(direction: Direction): Vector {
     return DirectionMap.get(direction)!;
   }
-  // Build a list of positions to traverse in the right order
-  private buildTraversals(vector: Vector): { x: number[]; y: number[] } {
-    const traversals: { x: number[]; y: number[] } = { x: [], y: [] };
-    for (let pos = 0; pos < Size; pos++) {
-      traversals.x.push(pos);
-      traversals.y.push(pos);
-    }
-    // Always traverse from the farthest position in the chosen direction
-    if (vector.x === 1) traversals.x = traversals.x.reverse();
-    if (vector.y === 1) traversals.y = traversals.y.reverse();
-    return traversals;
-  }
-  private findFarthestPosition(
-    position: Position,
-    vector: Vector
-  ): { farthest: Position; next: Position } {
-    let previous;
-    // Progress towards the vector direction until an obstacle is found
-    do {
-      previous = position;
-      position = { x: previous.x + vector.x, y: previous.y + vector.y };
-    } while (
-      this.grid.withinBounds(position) &&
-      this.grid.tileAvailable(position)
-    );
-    return {
-      farthest: previous,
-      next: position // Used to check if a merge is required
-    };
-  }
   private movesAvailable(): boolean {
     return this.grid.tilesAvailable() || this.tileMatchesAvailable();
   }
@@ -286,7 +296,4 @@ export default class GameManager {
     }
     return false;
   }
-  private positionsEqual(first: Position, second: Position): boolean {
-    return first.x === second.x && first.y === second.y;
-  }
 }
@@ -10,4 +10,7 @@ export interface GameState {
   won: boolean;
   keepPlaying: boolean;
   aiStrength: number;
+  // Missing from games saved before records were kept
+  recordStart?: number[];
+  recordTurns?: number[];
 }
@@ -72,6 +72,14 @@ export class HTMLActuator {
       this.throttleButton.textContent = "Throttle";
     }
   }
+  // Lets the browser save `text` as a file
+  public download(fileName: string, text: string): void {
+    const link = document.createElement("a");
+    link.href = URL.createObjectURL(new Blob([text], { type: "text/plain" }));
+    link.download = fileName;
+    link.click();
+    URL.revokeObjectURL(link.href);
+  }
   private clearContainer(container: Element): void {
     while (container.firstChild) {
       container.removeChild(container.firstChild);
@@ -57,6 +57,7 @@ export default class InputManager {
     this.bindButtonPress(".restart-button", this.restart);
     this.bindButtonPress(".run-button", this.run);
     this.bindButtonPress(".throttle-button", this.throttle);
+    this.bindButtonPress(".record-button", this.saveRecord);
     this.bindButtonPress(".plus-button", this.plus);
     this.bindButtonPress(".minus-button", this.minus);
     this.bindButtonPress(".keep-playing-button", this.keepPlaying);
@@ -124,6 +125,10 @@ export default class InputManager {
     event.preventDefault();
     this.gameManager.toggleThrottle();
   }
+  private saveRecord(event: Event) {
+    event.preventDefault();
+    this.gameManager.saveRecord();
+  }
   private plus(event: Event) {
     event.preventDefault();
     this.gameManager.plus();
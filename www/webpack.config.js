@@ -13,7 +13,13 @@ const appConfig = {
   entry: "main.ts",
   devtool: "cheap-module-source-map",
   devServer: {
-    contentBase: dist
+    contentBase: dist,
+    // Cross-origin isolation, without which browsers don't share memory with the worker and
+    // the AI can't be stopped in the middle of a move
+    headers: {
+      "Cross-Origin-Opener-Policy": "same-origin",
+      "Cross-Origin-Embedder-Policy": "require-corp"
+    }
   },
   plugins: [new HtmlWebpackPlugin({ template: "index.html" })],
   module: {
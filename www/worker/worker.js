@@ -5,7 +5,79 @@ const mod = import("../../ai2048-wasm/pkg").then(m => {
   return m;
 });
 
+// A game played entirely in wasm, for benchmarks
+let session = null;
+
+// Where the page bumps the epoch to stop searches, see `CancellationToken`. Replaced by the
+// page's own flag when memory can be shared with it.
+let cancelFlag = new Int32Array(1);
+
 registerPromiseWorker(async message => {
   const ai = await mod;
+  if (message.cancelFlag !== undefined) {
+    cancelFlag = message.cancelFlag;
+    return null;
+  }
+  if (message.newGame !== undefined) {
+    session = new ai.GameSession(message.newGame);
+  }
+  if (message.autoPlay !== undefined) {
+    // Rejects the page's promise instead of failing on a null session
+    if (session === null) {
+      throw new Error("There's no game to play: send newGame first");
+    }
+    session.auto_play(message.minProb, message.autoPlay);
+  }
+  if (message.newGame !== undefined || message.autoPlay !== undefined) {
+    return {
+      grid: session.grid(),
+      score: session.score(),
+      moves: session.moves(),
+      over: session.is_over()
+    };
+  }
+  if (message.traceMove !== undefined) {
+    return ai.trace_move(message.grid, message.traceMove);
+  }
+  if (message.writeRecord) {
+    return ai.write_record(message.start, message.turns);
+  }
+  if (message.explain) {
+    return ai.explain_position(message.grid, message.minProb);
+  }
+  if (message.analyze) {
+    if (message.budgetMs !== undefined) {
+      return ai.analyze_position_timed(message.grid, message.budgetMs);
+    }
+    return ai.analyze_position(message.grid, message.minProb);
+  }
+  // A flag the page can't write to would never match a later epoch, so only a shared one can
+  // cancel a search
+  if (
+    message.epoch !== undefined &&
+    typeof SharedArrayBuffer !== "undefined" &&
+    cancelFlag.buffer instanceof SharedArrayBuffer
+  ) {
+    const token = new ai.CancellationToken(cancelFlag, message.epoch);
+    try {
+      if (message.budgetMs !== undefined) {
+        return ai.evaluate_position_timed_cancellable(
+          message.grid,
+          message.budgetMs,
+          token
+        );
+      }
+      return ai.evaluate_position_cancellable(
+        message.grid,
+        message.minProb,
+        token
+      );
+    } finally {
+      token.free();
+    }
+  }
+  if (message.budgetMs !== undefined) {
+    return ai.evaluate_position_timed(message.grid, message.budgetMs);
+  }
   return ai.evaluate_position(message.grid, message.minProb);
 });